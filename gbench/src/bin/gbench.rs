@@ -3,7 +3,7 @@ use generic_array::sequence::GenericSequence;
 use generic_array::typenum::{U11, U8};
 use generic_array::GenericArray;
 use log::info;
-use neptune::batch_hasher::BatcherType;
+use neptune::batch_hasher::{BatcherConfig, BatcherType};
 use neptune::column_tree_builder::{ColumnTreeBuilder, ColumnTreeBuilderTrait};
 use neptune::error::Error;
 use neptune::BatchHasher;
@@ -16,15 +16,16 @@ fn bench_column_building(
     i: i32,
     batcher_type: Option<BatcherType>,
     leaves: usize,
-    max_column_batch_size: usize,
-    max_tree_batch_size: usize,
+    column_config: BatcherConfig,
+    tree_config: BatcherConfig,
 ) -> Fr {
     info!("[{}] Creating ColumnTreeBuilder", i);
     let mut builder = ColumnTreeBuilder::<U11, U8>::new(
         batcher_type,
         leaves,
-        max_column_batch_size,
-        max_tree_batch_size,
+        column_config,
+        tree_config,
+        0,
     )
     .unwrap();
     info!("[{}] ColumnTreeBuilder created", i);
@@ -105,6 +106,9 @@ fn main() -> Result<(), Error> {
     info!("max column batch size: {}", max_column_batch_size);
     info!("max tree batch size: {}", max_tree_batch_size);
 
+    let column_config = BatcherConfig::from_env(max_column_batch_size)?;
+    let tree_config = BatcherConfig::from_env(max_tree_batch_size)?;
+
     rayon::scope(|_| {
         (0..8).into_par_iter().for_each(|i| {
             info!("--> Run {}", i);
@@ -112,8 +116,8 @@ fn main() -> Result<(), Error> {
                 i,
                 Some(BatcherType::GPU),
                 leaves,
-                max_column_batch_size,
-                max_tree_batch_size,
+                column_config.clone(),
+                tree_config.clone(),
             );
         });
     });
@@ -0,0 +1,262 @@
+use crate::batch_hasher::BatcherConfig;
+use crate::error::Error;
+use crate::retry::{retry, ExponentialBackoff, RetryPolicy};
+use crate::{Arity, BatchHasher, Strength};
+use generic_array::GenericArray;
+use log::warn;
+use paired::bls12_381::Fr;
+use std::marker::PhantomData;
+use std::mem;
+use std::sync::Arc;
+
+/// `HashBackend` abstracts a device which hashes batches of `A`-sized preimages, such as a GPU.
+///
+/// A backend is driven through its lifecycle by `DeviceBatchHasher`: `init` once, then `upload_constants` once, then
+/// any number of `submit`s, with `sync` whenever all submitted work must be complete, and finally `release` once.
+pub trait HashBackend<A>
+where
+    A: Arity<Fr>,
+{
+    /// Acquire and initialize the device.
+    fn init(&mut self) -> Result<(), Error>;
+
+    /// Upload the constants for hashing with `strength` to the device.
+    fn upload_constants(&mut self, strength: Strength) -> Result<(), Error>;
+
+    /// Hash a batch of preimages on the device, returning one hash per preimage.
+    fn submit(&mut self, preimages: &[GenericArray<Fr, A>]) -> Result<Vec<Fr>, Error>;
+
+    /// Wait until all work submitted to the device is complete.
+    fn sync(&mut self) -> Result<(), Error>;
+
+    /// Free the device resources held for hashing, such as uploaded constants and caches. Called after `sync`, and
+    /// the backend is not used afterwards.
+    fn release(&mut self) -> Result<(), Error>;
+
+    /// Maximum number of preimages per `submit`, if the backend limits it, such as to the device memory it reserved
+    /// in `init`.
+    fn max_batch_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// Number of leaves of the trees which `build_tree` builds, if the backend has a whole-tree entry point for the
+    /// uploaded constants.
+    fn whole_tree_leaves(&self) -> Option<usize> {
+        None
+    }
+
+    /// Build the tree with base row `leaves` in one call, returning every row above the base, from the first to the
+    /// root. Only called with `whole_tree_leaves()` leaves.
+    fn build_tree(&mut self, _leaves: &[Fr]) -> Result<Vec<Fr>, Error> {
+        Err(Error::Other(
+            "backend has no whole-tree entry point".to_string(),
+        ))
+    }
+}
+
+impl<A, B> HashBackend<A> for Box<B>
+where
+    A: Arity<Fr>,
+    B: HashBackend<A> + ?Sized,
+{
+    fn init(&mut self) -> Result<(), Error> {
+        (**self).init()
+    }
+
+    fn upload_constants(&mut self, strength: Strength) -> Result<(), Error> {
+        (**self).upload_constants(strength)
+    }
+
+    fn submit(&mut self, preimages: &[GenericArray<Fr, A>]) -> Result<Vec<Fr>, Error> {
+        (**self).submit(preimages)
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        (**self).sync()
+    }
+
+    fn release(&mut self) -> Result<(), Error> {
+        (**self).release()
+    }
+
+    fn max_batch_size(&self) -> Option<usize> {
+        (**self).max_batch_size()
+    }
+
+    fn whole_tree_leaves(&self) -> Option<usize> {
+        (**self).whole_tree_leaves()
+    }
+
+    fn build_tree(&mut self, leaves: &[Fr]) -> Result<Vec<Fr>, Error> {
+        (**self).build_tree(leaves)
+    }
+}
+
+/// `BackendFactory` creates the backends of `BatcherType::Custom` batchers, so tree builders can hash on any
+/// `HashBackend`. Builders create a batcher for each build, so call it once per build.
+pub type BackendFactory<A> =
+    Arc<dyn Fn() -> Result<Box<dyn HashBackend<A> + Send>, Error> + Send + Sync>;
+
+/// Number of bytes of device memory needed per preimage of a batch: the preimage, and its hash.
+pub fn bytes_per_preimage<A: Arity<Fr>>() -> usize {
+    (A::to_usize() + 1) * mem::size_of::<Fr>()
+}
+
+/// `DeviceBatchHasher` implements `BatchHasher` on any `HashBackend`, retrying failed batches according to its
+/// `RetryPolicy` (by default, the `ExponentialBackoff` configured by its `BatcherConfig`).
+///
+/// Batches are submitted in parts of at most `max_batch_size` preimages, which is limited by the configured memory
+/// budget, if any. The backend must be released explicitly with `release`, which reports any error. Dropping a hasher
+/// does not touch the device: a hasher dropped without being released only logs a warning.
+pub struct DeviceBatchHasher<B, A>
+where
+    B: HashBackend<A>,
+    A: Arity<Fr>,
+{
+    backend: B,
+    max_batch_size: usize,
+    retry_policy: Box<dyn RetryPolicy + Send + Sync>,
+    released: bool,
+    _a: PhantomData<A>,
+}
+
+impl<B, A> DeviceBatchHasher<B, A>
+where
+    B: HashBackend<A>,
+    A: Arity<Fr>,
+{
+    /// Initialize `backend`, and upload the constants for `strength` to it.
+    pub fn new(mut backend: B, strength: Strength, config: &BatcherConfig) -> Result<Self, Error> {
+        config.validate()?;
+
+        let mut max_batch_size = match config.memory_budget {
+            Some(budget) => usize::min(config.max_batch_size, budget / bytes_per_preimage::<A>()),
+            None => config.max_batch_size,
+        };
+        if max_batch_size == 0 {
+            return Err(Error::InvalidConfiguration(format!(
+                "memory_budget must be at least {} bytes, for one preimage of arity {}",
+                bytes_per_preimage::<A>(),
+                A::to_usize()
+            )));
+        }
+
+        backend.init()?;
+        if let Some(limit) = backend.max_batch_size() {
+            max_batch_size = usize::min(max_batch_size, limit);
+        }
+        if let Err(e) = backend.upload_constants(strength) {
+            if let Err(release_error) = backend.release() {
+                warn!("Failed to release backend: {}", release_error);
+            }
+            return Err(e);
+        }
+
+        Ok(Self {
+            backend,
+            max_batch_size,
+            retry_policy: Box::new(ExponentialBackoff::from_config(config)),
+            released: false,
+            _a: PhantomData::<A>,
+        })
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Retry failed batches according to `policy`.
+    pub fn set_retry_policy<P>(&mut self, policy: P)
+    where
+        P: 'static + RetryPolicy + Send + Sync,
+    {
+        self.retry_policy = Box::new(policy);
+    }
+
+    pub fn is_released(&self) -> bool {
+        self.released
+    }
+
+    /// Number of leaves of the trees `build_tree` builds in one call, if the backend can.
+    pub fn whole_tree_leaves(&self) -> Option<usize> {
+        if self.released {
+            None
+        } else {
+            self.backend.whole_tree_leaves()
+        }
+    }
+
+    /// Build the tree with base row `leaves` in one call, retrying as for batches. Returns every row above the base.
+    pub fn build_tree(&mut self, leaves: &[Fr]) -> Result<Vec<Fr>, Error> {
+        self.check_not_released()?;
+        if self.backend.whole_tree_leaves() != Some(leaves.len()) {
+            return Err(Error::Other(format!(
+                "backend cannot build a tree of {} leaves in one call",
+                leaves.len()
+            )));
+        }
+
+        let backend = &mut self.backend;
+        retry(&*self.retry_policy, || backend.build_tree(leaves))
+    }
+
+    fn check_not_released(&self) -> Result<(), Error> {
+        if self.released {
+            Err(Error::Other(
+                "DeviceBatchHasher used after release".to_string(),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<B, A> BatchHasher<A> for DeviceBatchHasher<B, A>
+where
+    B: HashBackend<A>,
+    A: Arity<Fr>,
+{
+    fn hash(&mut self, preimages: &[GenericArray<Fr, A>]) -> Result<Vec<Fr>, Error> {
+        self.check_not_released()?;
+
+        let backend = &mut self.backend;
+        let mut hashes = Vec::with_capacity(preimages.len());
+        for part in preimages.chunks(self.max_batch_size) {
+            hashes.extend(retry(&*self.retry_policy, || backend.submit(part))?);
+        }
+        Ok(hashes)
+    }
+
+    fn max_batch_size(&self) -> usize {
+        self.max_batch_size
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        self.check_not_released()?;
+        self.backend.sync()
+    }
+
+    /// Wait for all submitted work, then release the backend. Releasing again does nothing.
+    fn release(&mut self) -> Result<(), Error> {
+        if self.released {
+            return Ok(());
+        }
+        self.released = true;
+
+        let synced = self.backend.sync();
+        self.backend.release()?;
+        synced
+    }
+}
+
+impl<B, A> Drop for DeviceBatchHasher<B, A>
+where
+    B: HashBackend<A>,
+    A: Arity<Fr>,
+{
+    fn drop(&mut self) {
+        if !self.released {
+            warn!("DeviceBatchHasher dropped without being released");
+        }
+    }
+}
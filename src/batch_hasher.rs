@@ -1,28 +1,156 @@
+use crate::backend::{BackendFactory, DeviceBatchHasher, HashBackend};
 use crate::error::Error;
+use crate::fallback::FallbackBatcher;
 use crate::poseidon::SimplePoseidonBatchHasher;
+use crate::verify::VerifyingBatchHasher;
 use crate::{Arity, BatchHasher, Strength, DEFAULT_STRENGTH};
 use generic_array::GenericArray;
 use paired::bls12_381::Fr;
+use std::env;
 use std::marker::PhantomData;
+use std::str::FromStr;
+use std::time::Duration;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum BatcherType {
+    /// A Futhark kernel on a GPU. Only available with the `gpu` feature.
     GPU,
     CPU,
+    /// A user-supplied `HashBackend`, created by the `BackendFactory` given to the tree builder.
+    Custom,
 }
 
-#[cfg(not(target_os = "macos"))]
-use crate::gpu::GPUBatchHasher;
+/// Maximum number of GPU kernels (Futhark contexts) across all devices.
+pub const MAX_KERNELS: usize = 32;
+
+/// `BatcherConfig` configures the batchers created by `Batcher::new` and the tree builders.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatcherConfig {
+    /// Number of GPUs to spread kernels over.
+    pub device_count: usize,
+    /// Number of kernels to run on each GPU. At most `MAX_KERNELS` kernels may be run in total.
+    pub kernels_per_device: usize,
+    /// Number of times a GPU batch is attempted before giving up.
+    pub retry_count: usize,
+    /// Time to wait before the first retry of a failed GPU batch. Each further retry waits twice as long as the last.
+    pub retry_backoff: Duration,
+    /// Time after the first attempt at a GPU batch after which it is no longer retried, if any.
+    pub retry_deadline: Option<Duration>,
+    /// Maximum number of preimages hashed in one batch.
+    pub max_batch_size: usize,
+    /// Maximum number of bytes of device memory each context may use for the preimages and hashes of batches, if
+    /// any. Hashers sharing a context share its budget: each reserves room for a batch of `max_batch_size`
+    /// preimages when it is created, or for as many as the rest of the budget allows. Larger batches are hashed in
+    /// parts.
+    pub memory_budget: Option<usize>,
+    /// Whether to continue hashing on the CPU when a device fails, rather than returning its error. See
+    /// `FallbackBatcher`.
+    pub cpu_fallback: bool,
+    /// Number of hashes of each device batch to re-compute on the CPU, to catch faulty devices. Zero disables
+    /// verification. See `VerifyingBatchHasher`.
+    pub verification_samples: usize,
+}
+
+impl BatcherConfig {
+    /// Create a configuration with default device and retry settings, and the given `max_batch_size`.
+    pub fn new(max_batch_size: usize) -> Self {
+        Self {
+            device_count: 1,
+            kernels_per_device: 4,
+            retry_count: 10,
+            retry_backoff: Duration::from_secs(1),
+            retry_deadline: Some(Duration::from_secs(300)),
+            max_batch_size,
+            memory_budget: None,
+            cpu_fallback: false,
+            verification_samples: 0,
+        }
+    }
+
+    /// Create a configuration as by `new`, overriding device and retry settings with the `GPU_COUNT`,
+    /// `KERNEL_PER_GPU` and `P2_RETRY` environment variables, if set. Returns `Error::InvalidConfiguration` if any is
+    /// malformed, or if the resulting configuration is invalid.
+    pub fn from_env(max_batch_size: usize) -> Result<Self, Error> {
+        let mut config = Self::new(max_batch_size);
+
+        if let Some(n) = env_var("GPU_COUNT")? {
+            config.device_count = n;
+        }
+        if let Some(n) = env_var("KERNEL_PER_GPU")? {
+            config.kernels_per_device = n;
+        }
+        if let Some(n) = env_var("P2_RETRY")? {
+            config.retry_count = n;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Total number of kernels, across all devices.
+    pub fn kernel_count(&self) -> usize {
+        self.device_count * self.kernels_per_device
+    }
+
+    /// Check that every value is in range.
+    pub fn validate(&self) -> Result<(), Error> {
+        let invalid = |reason: String| Err(Error::InvalidConfiguration(reason));
+
+        if self.device_count == 0 {
+            return invalid("device_count must be at least 1".to_string());
+        }
+        if self.kernels_per_device == 0 {
+            return invalid("kernels_per_device must be at least 1".to_string());
+        }
+        if self.device_count > MAX_KERNELS
+            || self.kernels_per_device > MAX_KERNELS
+            || self.kernel_count() > MAX_KERNELS
+        {
+            return invalid(format!(
+                "{} devices with {} kernels each exceeds the maximum of {} kernels",
+                self.device_count, self.kernels_per_device, MAX_KERNELS
+            ));
+        }
+        if self.retry_count == 0 {
+            return invalid("retry_count must be at least 1".to_string());
+        }
+        if self.max_batch_size == 0 {
+            return invalid("max_batch_size must be at least 1".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse environment variable `name`, if it is set.
+fn env_var<T: FromStr>(name: &str) -> Result<Option<T>, Error> {
+    match env::var(name) {
+        Ok(val) => val.parse::<T>().map(Some).map_err(|_| {
+            Error::InvalidConfiguration(format!("{}={:?} is not a valid number", name, val))
+        }),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(env::VarError::NotUnicode(_)) => Err(Error::InvalidConfiguration(format!(
+            "{} is not valid unicode",
+            name
+        ))),
+    }
+}
+
+#[cfg(all(feature = "gpu", not(target_os = "macos")))]
+use crate::gpu::{FutharkBackend, GPUBatchHasher};
 
 pub enum Batcher<'a, A>
 where
     A: Arity<Fr>,
 {
-    #[cfg(not(target_os = "macos"))]
-    GPU(GPUBatchHasher<'a, A>),
-    #[cfg(target_os = "macos")]
+    #[cfg(all(feature = "gpu", not(target_os = "macos")))]
+    GPU(GPUBatchHasher<A>),
+    #[cfg(not(all(feature = "gpu", not(target_os = "macos"))))]
     GPU(NoGPUBatchHasher<A>),
     CPU(SimplePoseidonBatchHasher<'a, A>),
+    Custom(DeviceBatchHasher<Box<dyn HashBackend<A> + Send>, A>),
+    Fallback(Box<FallbackBatcher<'a, A>>),
+    Verifying(Box<VerifyingBatchHasher<Batcher<'a, A>, A>>),
 }
 
 impl<A> Batcher<'_, A>
@@ -33,30 +161,112 @@ where
         match self {
             Batcher::GPU(_) => BatcherType::GPU,
             Batcher::CPU(_) => BatcherType::CPU,
+            Batcher::Custom(_) => BatcherType::Custom,
+            Batcher::Fallback(batcher) => batcher.active(),
+            Batcher::Verifying(batcher) => batcher.inner().t(),
         }
     }
 
-    pub(crate) fn new(t: &BatcherType, max_batch_size: usize) -> Result<Self, Error> {
-        Self::new_with_strength(DEFAULT_STRENGTH, t, max_batch_size)
+    /// Number of leaves of the trees this batcher can build in one call, if any. Only device batchers can: fallback
+    /// and verifying batchers hash row by row, so each batch can fall back or be verified.
+    pub(crate) fn whole_tree_leaves(&self) -> Option<usize> {
+        match self {
+            #[cfg(all(feature = "gpu", not(target_os = "macos")))]
+            Batcher::GPU(batcher) => batcher.whole_tree_leaves(),
+            Batcher::Custom(batcher) => batcher.whole_tree_leaves(),
+            _ => None,
+        }
+    }
+
+    /// Build the tree with base row `leaves` in one call, returning every row above the base. See
+    /// `HashBackend::build_tree`.
+    pub(crate) fn build_whole_tree(&mut self, leaves: &[Fr]) -> Result<Vec<Fr>, Error> {
+        match self {
+            #[cfg(all(feature = "gpu", not(target_os = "macos")))]
+            Batcher::GPU(batcher) => batcher.build_tree(leaves),
+            Batcher::Custom(batcher) => batcher.build_tree(leaves),
+            _ => Err(Error::Other(
+                "batcher cannot build a tree in one call".to_string(),
+            )),
+        }
+    }
+
+    pub(crate) fn new(t: &BatcherType, config: &BatcherConfig) -> Result<Self, Error> {
+        Self::new_with_strength(DEFAULT_STRENGTH, t, config)
     }
 
     pub(crate) fn new_with_strength(
         strength: Strength,
         t: &BatcherType,
-        max_batch_size: usize,
+        config: &BatcherConfig,
+    ) -> Result<Self, Error> {
+        Self::with_factory(strength, t, config, None)
+    }
+
+    /// Create a batcher of type `t`, creating the backend of a `BatcherType::Custom` batcher with `factory`.
+    pub(crate) fn with_factory(
+        strength: Strength,
+        t: &BatcherType,
+        config: &BatcherConfig,
+        factory: Option<&BackendFactory<A>>,
     ) -> Result<Self, Error> {
+        config.validate()?;
+
+        if config.cpu_fallback && *t != BatcherType::CPU {
+            let primary_config = BatcherConfig {
+                cpu_fallback: false,
+                ..config.clone()
+            };
+            return Ok(Batcher::Fallback(Box::new(FallbackBatcher::from_primary(
+                Batcher::with_factory(strength, t, &primary_config, factory),
+                *t,
+                strength,
+                &primary_config,
+            )?)));
+        }
+        if config.verification_samples > 0 && *t != BatcherType::CPU {
+            let unverified = BatcherConfig {
+                verification_samples: 0,
+                ..config.clone()
+            };
+            return Ok(Batcher::Verifying(Box::new(VerifyingBatchHasher::new(
+                Batcher::with_factory(strength, t, &unverified, factory)?,
+                strength,
+                config.verification_samples,
+            ))));
+        }
+
         match t {
             #[cfg(all(feature = "gpu", target_os = "macos"))]
-            BatcherType::GPU => panic!("GPU unimplemented on macos"),
+            BatcherType::GPU => Err(Error::GPUError(
+                "GPU batchers are not supported on macos".to_string(),
+            )),
             #[cfg(all(feature = "gpu", not(target_os = "macos")))]
-            BatcherType::GPU => Ok(Batcher::GPU(GPUBatchHasher::<A>::new_with_strength(
+            BatcherType::GPU => Ok(Batcher::GPU(GPUBatchHasher::<A>::new(
+                FutharkBackend::new::<A>(config)?,
                 strength,
-                max_batch_size,
+                config,
             )?)),
+            #[cfg(not(feature = "gpu"))]
+            BatcherType::GPU => Err(Error::GPUError(
+                "neptune was built without the gpu feature".to_string(),
+            )),
 
             BatcherType::CPU => Ok(Batcher::CPU(
-                SimplePoseidonBatchHasher::<A>::new_with_strength(strength, max_batch_size)?,
+                SimplePoseidonBatchHasher::<A>::new_with_strength(strength, config.max_batch_size)?,
             )),
+            BatcherType::Custom => {
+                let factory = factory.ok_or_else(|| {
+                    Error::InvalidConfiguration(
+                        "a custom batcher needs a backend factory".to_string(),
+                    )
+                })?;
+                Ok(Batcher::Custom(DeviceBatchHasher::new(
+                    factory()?,
+                    strength,
+                    config,
+                )?))
+            }
         }
     }
 }
@@ -69,6 +279,9 @@ where
         match self {
             Batcher::GPU(batcher) => batcher.hash(preimages),
             Batcher::CPU(batcher) => batcher.hash(preimages),
+            Batcher::Custom(batcher) => batcher.hash(preimages),
+            Batcher::Fallback(batcher) => batcher.hash(preimages),
+            Batcher::Verifying(batcher) => batcher.hash(preimages),
         }
     }
 
@@ -76,23 +289,172 @@ where
         match self {
             Batcher::GPU(batcher) => batcher.max_batch_size(),
             Batcher::CPU(batcher) => batcher.max_batch_size(),
+            Batcher::Custom(batcher) => batcher.max_batch_size(),
+            Batcher::Fallback(batcher) => batcher.max_batch_size(),
+            Batcher::Verifying(batcher) => batcher.max_batch_size(),
+        }
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        match self {
+            Batcher::GPU(batcher) => batcher.sync(),
+            Batcher::CPU(batcher) => batcher.sync(),
+            Batcher::Custom(batcher) => batcher.sync(),
+            Batcher::Fallback(batcher) => batcher.sync(),
+            Batcher::Verifying(batcher) => batcher.sync(),
+        }
+    }
+
+    fn release(&mut self) -> Result<(), Error> {
+        match self {
+            Batcher::GPU(batcher) => batcher.release(),
+            Batcher::CPU(batcher) => batcher.release(),
+            Batcher::Custom(batcher) => batcher.release(),
+            Batcher::Fallback(batcher) => batcher.release(),
+            Batcher::Verifying(batcher) => batcher.release(),
         }
     }
 }
 
-// /// NoGPUBatchHasher is a dummy required so we can build with the gpu flag even on platforms on which we cannot currently
-// /// run with GPU.
-pub struct NoGPUBatchHasher<A>(PhantomData<A>);
+/// `NoGPUBatchHasher` takes the place of `GPUBatchHasher` in builds which cannot hash on a GPU: those without the
+/// `gpu` feature, and those on macos. It cannot be created, so `Batcher::GPU` is never constructed in such builds.
+pub struct NoGPUBatchHasher<A>(NoGPU, PhantomData<A>);
+
+/// Uninhabited, so a `NoGPUBatchHasher` cannot exist.
+enum NoGPU {}
 
 impl<A> BatchHasher<A> for NoGPUBatchHasher<A>
 where
     A: Arity<Fr>,
 {
     fn hash(&mut self, _preimages: &[GenericArray<Fr, A>]) -> Result<Vec<Fr>, Error> {
-        unimplemented!();
+        match self.0 {}
     }
 
     fn max_batch_size(&self) -> usize {
-        unimplemented!();
+        match self.0 {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_device::fake_backends;
+
+    #[test]
+    fn test_batcher_config() {
+        let config = BatcherConfig::new(512);
+        assert!(config.validate().is_ok());
+        assert_eq!(4, config.kernel_count());
+
+        let invalid_configs = vec![
+            BatcherConfig {
+                device_count: 0,
+                ..config.clone()
+            },
+            BatcherConfig {
+                kernels_per_device: 0,
+                ..config.clone()
+            },
+            BatcherConfig {
+                device_count: 9,
+                ..config.clone()
+            },
+            BatcherConfig {
+                retry_count: 0,
+                ..config.clone()
+            },
+            BatcherConfig {
+                max_batch_size: 0,
+                ..config.clone()
+            },
+        ];
+        for invalid in invalid_configs {
+            match invalid.validate() {
+                Err(Error::InvalidConfiguration(_)) => (),
+                other => panic!("unexpected result for {:?}: {:?}", invalid, other),
+            }
+            assert!(
+                Batcher::<generic_array::typenum::U8>::new(&BatcherType::CPU, &invalid).is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn test_batcher_custom() {
+        let config = BatcherConfig::new(512);
+
+        // A custom batcher needs a backend factory.
+        match Batcher::<generic_array::typenum::U8>::new(&BatcherType::Custom, &config) {
+            Err(Error::InvalidConfiguration(_)) => (),
+            _ => panic!("expected an invalid configuration"),
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "gpu"))]
+    fn test_batcher_without_gpu() {
+        let config = BatcherConfig::new(512);
+
+        // GPU batchers are an error, rather than a panic, without the gpu feature.
+        match Batcher::<generic_array::typenum::U8>::new(&BatcherType::GPU, &config) {
+            Err(Error::GPUError(_)) => (),
+            _ => panic!("expected a GPU error"),
+        }
+    }
+
+    #[test]
+    fn test_batcher_cpu_fallback() {
+        let config = BatcherConfig {
+            cpu_fallback: true,
+            ..BatcherConfig::new(512)
+        };
+
+        let (_, factory) = fake_backends();
+        let batcher = Batcher::<generic_array::typenum::U8>::with_factory(
+            Strength::Standard,
+            &BatcherType::Custom,
+            &config,
+            Some(&factory),
+        )
+        .unwrap();
+        match batcher {
+            Batcher::Fallback(_) => (),
+            _ => panic!("expected a fallback batcher"),
+        }
+        assert_eq!(BatcherType::Custom, batcher.t());
+
+        // The CPU needs no fallback.
+        let batcher =
+            Batcher::<generic_array::typenum::U8>::new(&BatcherType::CPU, &config).unwrap();
+        assert_eq!(BatcherType::CPU, batcher.t());
+    }
+
+    #[test]
+    fn test_batcher_verification() {
+        use ff::Field;
+        use generic_array::sequence::GenericSequence;
+
+        let config = BatcherConfig {
+            verification_samples: 4,
+            ..BatcherConfig::new(512)
+        };
+
+        let (_, factory) = fake_backends();
+        let mut batcher = Batcher::<generic_array::typenum::U8>::with_factory(
+            Strength::Standard,
+            &BatcherType::Custom,
+            &config,
+            Some(&factory),
+        )
+        .unwrap();
+        assert_eq!(BatcherType::Custom, batcher.t());
+
+        let preimages = vec![GenericArray::generate(|_| Fr::one()); 16];
+        assert_eq!(16, batcher.hash(&preimages).unwrap().len());
+        match batcher {
+            Batcher::Verifying(verifying) => assert_eq!(4, verifying.stats().checked),
+            _ => panic!("expected a verifying batcher"),
+        }
     }
 }
@@ -0,0 +1,134 @@
+//! Hashing of bit and byte strings.
+//!
+//! Bit strings are packed into field elements as follows, so that every bit string has exactly one packing:
+//! - The first element is the length of the bit string, in bits.
+//! - Then, each successive chunk of (up to) `PACKED_BITS` bits is read as a little-endian integer: the first bit of
+//!   the chunk is the least significant. The last chunk may be shorter.
+//!
+//! Byte strings are hashed as the bit string of their bytes' bits, least significant bit first.
+//!
+//! The packed elements are absorbed into the rate (every element of the state but the first) of a Poseidon sponge,
+//! `arity` at a time, permuting after each (possibly partial) chunk. The result is the second element of the final
+//! state. The first element is initialized with a domain tag (`2^66`), which separates this from other uses of the
+//! permutation.
+//!
+//! `circuit::poseidon_hash_bits` hashes allocated bits in the same way.
+use crate::poseidon::{Arity, Poseidon, PoseidonConstants};
+use crate::scalar_from_u64;
+use ff::{Field, PrimeField, PrimeFieldRepr, ScalarEngine};
+
+/// Number of bits packed into each field element. 248 bits (31 bytes) always fit in a BLS12-381 scalar.
+pub const PACKED_BITS: usize = 248;
+
+/// The first element of the initial sponge state.
+pub(crate) fn domain_tag<E: ScalarEngine>() -> E::Fr {
+    let mut tag = scalar_from_u64::<E::Fr>(1 << 33);
+    tag.square();
+    tag
+}
+
+/// Convert `bytes` to bits, least significant bit of each byte first.
+pub fn bytes_to_bits_le(bytes: &[u8]) -> Vec<bool> {
+    bytes
+        .iter()
+        .flat_map(|byte| (0..8).map(move |i| (byte >> i) & 1 == 1))
+        .collect()
+}
+
+/// Pack `bits` into field elements, prefixed by their length.
+pub fn pack_bits<E: ScalarEngine>(bits: &[bool]) -> Vec<E::Fr> {
+    let mut packed = Vec::with_capacity(1 + (bits.len() + PACKED_BITS - 1) / PACKED_BITS);
+    packed.push(scalar_from_u64::<E::Fr>(bits.len() as u64));
+
+    for chunk in bits.chunks(PACKED_BITS) {
+        let mut repr = <E::Fr as PrimeField>::Repr::default();
+        for (i, bit) in chunk.iter().enumerate() {
+            if *bit {
+                repr.as_mut()[i / 64] |= 1 << (i % 64);
+            }
+        }
+        packed.push(E::Fr::from_repr(repr).expect("packed bits must be a valid field element"));
+    }
+
+    packed
+}
+
+/// Hash `bits`.
+pub fn hash_bits<E, A>(constants: &PoseidonConstants<E, A>, bits: &[bool]) -> E::Fr
+where
+    E: ScalarEngine,
+    A: Arity<E::Fr>,
+{
+    let mut p = Poseidon::new(constants);
+    p.elements[0] = domain_tag::<E>();
+
+    for chunk in pack_bits::<E>(bits).chunks(constants.arity()) {
+        for (i, element) in chunk.iter().enumerate() {
+            p.elements[i + 1].add_assign(element);
+        }
+        p.permute();
+    }
+
+    p.elements[1]
+}
+
+/// Hash `bytes`.
+pub fn hash_bytes<E, A>(constants: &PoseidonConstants<E, A>, bytes: &[u8]) -> E::Fr
+where
+    E: ScalarEngine,
+    A: Arity<E::Fr>,
+{
+    hash_bits(constants, &bytes_to_bits_le(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use generic_array::typenum::{U2, U8};
+    use paired::bls12_381::{Bls12, Fr};
+
+    #[test]
+    fn test_pack_bits() {
+        let packed = pack_bits::<Bls12>(&bytes_to_bits_le(&[1, 2, 255]));
+        assert_eq!(
+            vec![
+                scalar_from_u64::<Fr>(24),
+                scalar_from_u64::<Fr>(1 + (2 << 8) + (255 << 16))
+            ],
+            packed
+        );
+
+        // Every chunk but the last is full.
+        let packed = pack_bits::<Bls12>(&vec![true; PACKED_BITS + 3]);
+        assert_eq!(3, packed.len());
+        assert_eq!(scalar_from_u64::<Fr>(7), packed[2]);
+    }
+
+    #[test]
+    fn test_hash_bytes() {
+        test_hash_bytes_aux::<U2>();
+        test_hash_bytes_aux::<U8>();
+    }
+
+    fn test_hash_bytes_aux<A: Arity<Fr>>() {
+        let constants = PoseidonConstants::<Bls12, A>::new();
+        let bytes: Vec<u8> = (0..100).collect();
+
+        let hash = hash_bytes(&constants, &bytes);
+        assert_eq!(hash, hash_bits(&constants, &bytes_to_bits_le(&bytes)));
+
+        // The length is bound, so trailing zeros matter.
+        let mut padded = bytes.clone();
+        padded.push(0);
+        assert_ne!(hash, hash_bytes(&constants, &padded));
+        assert_ne!(hash_bytes(&constants, &[]), hash_bytes(&constants, &[0]));
+
+        // Hashing bytes differs from hashing their packing directly.
+        let packed = pack_bits::<Bls12>(&bytes_to_bits_le(&bytes[..31]));
+        let mut p = Poseidon::<Bls12, A>::new(&constants);
+        for element in packed.iter().take(constants.arity()) {
+            p.input(*element).unwrap();
+        }
+        assert_ne!(hash_bytes(&constants, &bytes[..31]), p.hash());
+    }
+}
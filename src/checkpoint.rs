@@ -0,0 +1,274 @@
+use crate::error::Error;
+use blake2s_simd::State;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use ff::PrimeField;
+use paired::bls12_381::{Fr, FrRepr};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Identifies (and versions) row checkpoint files.
+const MAGIC: &[u8; 8] = b"NPTNCKP1";
+const DIGEST_SIZE: usize = 32;
+const FR_SIZE: usize = 32;
+/// Magic, leaf count, arity, row, row length and leaves digest.
+const HEADER_SIZE: usize = MAGIC.len() + 4 * 8 + DIGEST_SIZE;
+
+/// `Checkpoint` persists the rows of a tree to a directory as they are completed, so a build which is interrupted
+/// (by a crash or cancellation) can resume from the last completed row instead of starting over.
+///
+/// Each row is written to its own file, atomically, with a header identifying the tree it belongs to (including a
+/// digest of the leaves) and a trailing digest of the whole file. Rows are only resumed if every check passes, so a
+/// checkpoint can never silently contribute hashes of other leaves to a tree.
+#[derive(Clone, Debug)]
+pub struct Checkpoint {
+    dir: PathBuf,
+}
+
+/// Identifies the tree whose rows are checkpointed.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct TreeId {
+    pub(crate) leaf_count: usize,
+    pub(crate) arity: usize,
+    pub(crate) leaves_digest: [u8; DIGEST_SIZE],
+}
+
+impl TreeId {
+    pub(crate) fn new(leaves: &[Fr], arity: usize) -> Self {
+        let mut state = State::new();
+        for leaf in leaves {
+            state.update(&fr_to_bytes(leaf));
+        }
+
+        Self {
+            leaf_count: leaves.len(),
+            arity,
+            leaves_digest: digest(state),
+        }
+    }
+}
+
+impl Checkpoint {
+    /// Checkpoint rows to `dir`, which is created if it does not exist.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Result<Self, Error> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).map_err(|e| io_error(&dir, e))?;
+
+        Ok(Self { dir })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Remove all checkpointed rows, including any left partially written by a crash. Other files in the directory
+    /// are left in place.
+    pub fn clear(&self) -> Result<(), Error> {
+        let entries = fs::read_dir(&self.dir).map_err(|e| io_error(&self.dir, e))?;
+        for entry in entries {
+            let path = entry.map_err(|e| io_error(&self.dir, e))?.path();
+            let name = path.file_name().and_then(|name| name.to_str());
+            if name.map_or(false, is_row_file_name) {
+                fs::remove_file(&path).map_err(|e| io_error(&path, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Persist `data` as the completed `row` (counting from the first row above the leaves) of tree `id`.
+    pub(crate) fn save_row(&self, id: &TreeId, row: usize, data: &[Fr]) -> Result<(), Error> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + data.len() * FR_SIZE + DIGEST_SIZE);
+        bytes.extend_from_slice(MAGIC);
+        for n in &[id.leaf_count, id.arity, row, data.len()] {
+            bytes.write_u64::<LittleEndian>(*n as u64).unwrap();
+        }
+        bytes.extend_from_slice(&id.leaves_digest);
+        for elt in data {
+            bytes.extend_from_slice(&fr_to_bytes(elt));
+        }
+        let mut state = State::new();
+        state.update(&bytes);
+        bytes.extend_from_slice(&digest(state));
+
+        // Write to a temporary file first, so a crash can never leave a partial row in place. The file is synced
+        // before it is renamed, and the directory after, so the row is durable once this returns.
+        let path = self.row_path(row);
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path).map_err(|e| io_error(&tmp_path, e))?;
+        file.write_all(&bytes)
+            .and_then(|_| file.sync_all())
+            .map_err(|e| io_error(&tmp_path, e))?;
+        fs::rename(&tmp_path, &path).map_err(|e| io_error(&path, e))?;
+        self.sync_dir()
+    }
+
+    /// Sync the directory, persisting renames within it. Directories cannot be opened as files on every platform,
+    /// so this is only done on Unix.
+    #[cfg(unix)]
+    fn sync_dir(&self) -> Result<(), Error> {
+        File::open(&self.dir)
+            .and_then(|dir| dir.sync_all())
+            .map_err(|e| io_error(&self.dir, e))
+    }
+
+    #[cfg(not(unix))]
+    fn sync_dir(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Load `row` of tree `id`, which must contain `len` elements. Returns `None` if the row was never checkpointed,
+    /// and `Error::InvalidCheckpoint` if it was, but fails verification.
+    pub(crate) fn load_row(
+        &self,
+        id: &TreeId,
+        row: usize,
+        len: usize,
+    ) -> Result<Option<Vec<Fr>>, Error> {
+        let path = self.row_path(row);
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(io_error(&path, e)),
+        };
+        let invalid = |reason: &str| {
+            Err(Error::InvalidCheckpoint(format!(
+                "{}: {}",
+                path.display(),
+                reason
+            )))
+        };
+
+        if bytes.len() != HEADER_SIZE + len * FR_SIZE + DIGEST_SIZE {
+            return invalid("unexpected size");
+        }
+        let (contents, file_digest) = bytes.split_at(bytes.len() - DIGEST_SIZE);
+        let mut state = State::new();
+        state.update(contents);
+        if digest(state) != file_digest {
+            return invalid("digest mismatch");
+        }
+
+        let mut reader = contents;
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic).unwrap();
+        if &magic != MAGIC {
+            return invalid("not a row checkpoint");
+        }
+        let mut header = [0usize; 4];
+        for n in header.iter_mut() {
+            *n = reader.read_u64::<LittleEndian>().unwrap() as usize;
+        }
+        let mut leaves_digest = [0u8; DIGEST_SIZE];
+        reader.read_exact(&mut leaves_digest).unwrap();
+        if header != [id.leaf_count, id.arity, row, len] || leaves_digest != id.leaves_digest {
+            return invalid("checkpoint is for a different tree");
+        }
+
+        let mut data = Vec::with_capacity(len);
+        for _ in 0..len {
+            let mut repr = FrRepr::default();
+            for limb in repr.0.iter_mut() {
+                *limb = reader.read_u64::<LittleEndian>().unwrap();
+            }
+            match Fr::from_repr(repr) {
+                Ok(elt) => data.push(elt),
+                Err(_) => return invalid("invalid field element"),
+            }
+        }
+
+        Ok(Some(data))
+    }
+
+    fn row_path(&self, row: usize) -> PathBuf {
+        self.dir.join(format!("row-{}", row))
+    }
+}
+
+/// Whether `name` is that of a row file written by `save_row`, complete (`row-N`) or temporary (`row-N.tmp`).
+fn is_row_file_name(name: &str) -> bool {
+    let name = if name.ends_with(".tmp") {
+        &name[..name.len() - ".tmp".len()]
+    } else {
+        name
+    };
+    if !name.starts_with("row-") {
+        return false;
+    }
+    let row = &name["row-".len()..];
+    !row.is_empty() && row.chars().all(|c| c.is_ascii_digit())
+}
+
+fn fr_to_bytes(fr: &Fr) -> [u8; FR_SIZE] {
+    let mut bytes = [0u8; FR_SIZE];
+    let mut writer = &mut bytes[..];
+    for limb in fr.into_repr().0.iter() {
+        writer.write_u64::<LittleEndian>(*limb).unwrap();
+    }
+    bytes
+}
+
+fn digest(state: State) -> [u8; DIGEST_SIZE] {
+    let mut digest = [0u8; DIGEST_SIZE];
+    digest.copy_from_slice(state.finalize().as_bytes());
+    digest
+}
+
+fn io_error(path: &Path, e: io::Error) -> Error {
+    Error::Other(format!("checkpoint I/O error at {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scalar_from_u64;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_checkpoint_rows() {
+        let tmp = TempDir::new("neptune-checkpoint").unwrap();
+        let checkpoint = Checkpoint::new(tmp.path()).unwrap();
+
+        let leaves: Vec<Fr> = (0..64).map(scalar_from_u64).collect();
+        let id = TreeId::new(&leaves, 8);
+        let row: Vec<Fr> = (0..8).map(|i| scalar_from_u64(i * 7)).collect();
+
+        assert!(checkpoint.load_row(&id, 0, 8).unwrap().is_none());
+
+        checkpoint.save_row(&id, 0, &row).unwrap();
+        assert_eq!(Some(row.clone()), checkpoint.load_row(&id, 0, 8).unwrap());
+
+        // A checkpoint of other leaves, or of a differently-shaped tree, is rejected.
+        let other_leaves: Vec<Fr> = (1..65).map(scalar_from_u64).collect();
+        let other_id = TreeId::new(&other_leaves, 8);
+        match checkpoint.load_row(&other_id, 0, 8) {
+            Err(Error::InvalidCheckpoint(_)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(checkpoint.load_row(&id, 0, 4).is_err());
+
+        // Any corruption is detected.
+        let path = tmp.path().join("row-0");
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[HEADER_SIZE + 3] ^= 1;
+        fs::write(&path, &bytes).unwrap();
+        match checkpoint.load_row(&id, 0, 8) {
+            Err(Error::InvalidCheckpoint(_)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // Clearing removes every row, even after a gap, and partially written rows, but nothing else.
+        checkpoint.save_row(&id, 2, &row).unwrap();
+        fs::write(tmp.path().join("row-3.tmp"), b"partial").unwrap();
+        let others = ["other", "other.tmp", "row-notes.tmp"];
+        for other in &others {
+            fs::write(tmp.path().join(other), b"other").unwrap();
+        }
+        checkpoint.clear().unwrap();
+        assert!(checkpoint.load_row(&id, 0, 8).unwrap().is_none());
+        assert!(checkpoint.load_row(&id, 2, 8).unwrap().is_none());
+        assert!(!tmp.path().join("row-3.tmp").exists());
+        for other in &others {
+            assert!(tmp.path().join(other).exists());
+        }
+    }
+}
@@ -1,6 +1,9 @@
+use crate::bytes;
+use crate::encryption;
 use crate::matrix::Matrix;
 use crate::mds::SparseMatrix;
 use crate::poseidon::{Arity, PoseidonConstants};
+use crate::transcript;
 
 use bellperson::gadgets::boolean::Boolean;
 use bellperson::gadgets::num;
@@ -14,14 +17,29 @@ use std::marker::PhantomData;
 /// extract into a `num::AllocatedNum`, enforcing that the linear combination corresponds to the result.
 /// In this way, all intermediate calculations are accounted for, with the restriction that we can only
 /// accumulate linear (not polynomial) constraints. The set of operations provided here ensure this invariant is maintained.
+///
+/// `Elt` is also the input type of `poseidon_hash_elts`, so callers can hash constants and linear combinations without
+/// first allocating them.
 #[derive(Clone)]
-enum Elt<E: Engine> {
+pub enum Elt<E: Engine> {
     Allocated(AllocatedNum<E>),
     Num(num::Num<E>),
 }
 
+impl<E: Engine> From<AllocatedNum<E>> for Elt<E> {
+    fn from(allocated: AllocatedNum<E>) -> Self {
+        Self::Allocated(allocated)
+    }
+}
+
+impl<E: Engine> From<num::Num<E>> for Elt<E> {
+    fn from(num: num::Num<E>) -> Self {
+        Self::Num(num)
+    }
+}
+
 impl<E: Engine> Elt<E> {
-    fn is_allocated(&self) -> bool {
+    pub fn is_allocated(&self) -> bool {
         if let Self::Allocated(_) = self {
             true
         } else {
@@ -29,7 +47,7 @@ impl<E: Engine> Elt<E> {
         }
     }
 
-    fn is_num(&self) -> bool {
+    pub fn is_num(&self) -> bool {
         if let Self::Num(_) = self {
             true
         } else {
@@ -37,12 +55,30 @@ impl<E: Engine> Elt<E> {
         }
     }
 
-    fn num_from_fr<CS: ConstraintSystem<E>>(fr: E::Fr) -> Self {
+    /// Returns true if this `Elt` is a known constant: a `Num` with a value, whose linear combination only involves
+    /// `CS::one()`. Constants are propagated through the circuit without allocation.
+    pub fn is_constant<CS: ConstraintSystem<E>>(&self) -> bool {
+        match self {
+            Self::Allocated(_) => false,
+            Self::Num(num) => {
+                num.get_value().is_some()
+                    && num
+                        .lc(E::Fr::one())
+                        .iter()
+                        .all(|(var, _)| var.get_unchecked() == CS::one().get_unchecked())
+            }
+        }
+    }
+
+    /// Create a constant `Elt`, whose linear combination is `fr` times `CS::one()`.
+    pub fn num_from_fr<CS: ConstraintSystem<E>>(fr: E::Fr) -> Self {
         let num = num::Num::<E>::zero();
         Self::Num(num.add_bool_with_coeff(CS::one(), &Boolean::Constant(true), fr))
     }
 
-    fn ensure_allocated<CS: ConstraintSystem<E>>(
+    /// Return the `AllocatedNum` for this `Elt`, allocating a `Num` (and enforcing it equals its linear combination,
+    /// if `enforce`).
+    pub fn ensure_allocated<CS: ConstraintSystem<E>>(
         &self,
         cs: &mut CS,
         enforce: bool,
@@ -68,29 +104,41 @@ impl<E: Engine> Elt<E> {
         }
     }
 
-    fn val(&self) -> Option<E::Fr> {
+    pub fn val(&self) -> Option<E::Fr> {
         match self {
             Self::Allocated(v) => v.get_value(),
             Self::Num(num) => num.get_value(),
         }
     }
 
-    fn lc(&self) -> LinearCombination<E> {
+    pub fn lc(&self) -> LinearCombination<E> {
         match self {
             Self::Num(num) => num.lc(E::Fr::one()),
             Self::Allocated(v) => LinearCombination::<E>::zero() + v.get_variable(),
         }
     }
 
-    /// Add two Nums and return a Num tracking the calculation. It is forbidden to invoke on an Allocated because the intended computation
-    /// does not include that path.
-    fn add<CS: ConstraintSystem<E>>(self, other: Elt<E>) -> Result<Elt<E>, SynthesisError> {
-        match (self, other) {
-            (Elt::Num(a), Elt::Num(b)) => Ok(Elt::Num(a.add(&b))),
-            _ => panic!("only two numbers may be added"),
+    fn into_num(self) -> num::Num<E> {
+        match self {
+            Self::Num(num) => num,
+            Self::Allocated(v) => v.into(),
         }
     }
 
+    /// Subtract `other`, returning a Num tracking the calculation.
+    fn sub(self, other: Elt<E>) -> Elt<E> {
+        let mut neg_one = E::Fr::one();
+        neg_one.negate();
+
+        Elt::Num(self.into_num().add(&other.into_num().scale(neg_one)))
+    }
+
+    /// Add `other`, returning a Num tracking the calculation. Allocated operands (as left by
+    /// `SynthesisStrategy::AllocateEveryRound`) are added as their variables.
+    fn add<CS: ConstraintSystem<E>>(self, other: Elt<E>) -> Result<Elt<E>, SynthesisError> {
+        Ok(Elt::Num(self.into_num().add(&other.into_num())))
+    }
+
     /// Scale
     fn scale<CS: ConstraintSystem<E>>(self, scalar: E::Fr) -> Result<Elt<E>, SynthesisError> {
         match self {
@@ -100,6 +148,27 @@ impl<E: Engine> Elt<E> {
     }
 }
 
+/// `SynthesisStrategy` selects how `PoseidonCircuit` trades constraint count against the density of its linear
+/// combinations. All strategies compute the same hash.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SynthesisStrategy {
+    /// Allocate each s-box input, so each s-box costs four constraints (three for an allocated or constant input).
+    /// Between s-boxes, elements are linear combinations, which grow through the partial rounds.
+    Standard,
+    /// Additionally allocate every element after every round, so linear combinations never span more than one
+    /// round's matrix product. Costs the most constraints, but keeps linear combinations smallest.
+    AllocateEveryRound,
+    /// Apply s-boxes directly to linear combinations, without allocating their inputs, so each s-box costs three
+    /// constraints. Costs the fewest constraints, but each input linear combination appears in all three.
+    FusedSBox,
+}
+
+impl Default for SynthesisStrategy {
+    fn default() -> Self {
+        SynthesisStrategy::Standard
+    }
+}
+
 /// Circuit for Poseidon hash.
 pub struct PoseidonCircuit<'a, E, A>
 where
@@ -112,6 +181,7 @@ where
     pos: usize,
     current_round: usize,
     constants: &'a PoseidonConstants<E, A>,
+    strategy: SynthesisStrategy,
     _w: PhantomData<A>,
 }
 
@@ -122,7 +192,11 @@ where
     A: Arity<E::Fr>,
 {
     /// Create a new Poseidon hasher for `preimage`.
-    fn new(elements: Vec<Elt<E>>, constants: &'a PoseidonConstants<E, A>) -> Self {
+    fn new(
+        elements: Vec<Elt<E>>,
+        constants: &'a PoseidonConstants<E, A>,
+        strategy: SynthesisStrategy,
+    ) -> Self {
         let width = constants.width();
 
         PoseidonCircuit {
@@ -132,6 +206,7 @@ where
             pos: width,
             current_round: 0,
             constants,
+            strategy,
             _w: PhantomData::<A>,
         }
     }
@@ -140,6 +215,13 @@ where
         &mut self,
         mut cs: CS,
     ) -> Result<AllocatedNum<E>, SynthesisError> {
+        self.permute(&mut cs)?;
+
+        self.elements[1].ensure_allocated(&mut cs.namespace(|| "hash result"), true)
+    }
+
+    /// Apply the permutation to `elements`, which afterward hold the full permuted state.
+    fn permute<CS: ConstraintSystem<E>>(&mut self, cs: &mut CS) -> Result<(), SynthesisError> {
         self.full_round(cs.namespace(|| "first round"), true, false)?;
 
         for i in 1..self.constants.full_rounds / 2 {
@@ -163,7 +245,7 @@ where
         }
         self.full_round(cs.namespace(|| "terminal full round"), false, true)?;
 
-        self.elements[1].ensure_allocated(&mut cs.namespace(|| "hash result"), true)
+        Ok(())
     }
 
     fn full_round<CS: ConstraintSystem<E>>(
@@ -208,24 +290,22 @@ where
                 None
             };
 
-            if first_round {
-                if i == 0 {
-                    // The very first s-box for the constant arity tag can also be computed statically, as a constant.
-                    self.elements[i] = constant_quintic_s_box_pre_add_tag::<CS, E>(
-                        &self.elements[i],
-                        pre_round_key,
-                        post_round_key,
-                    );
-                } else {
-                    self.elements[i] = quintic_s_box_pre_add(
-                        cs.namespace(|| format!("quintic s-box {}", i)),
-                        &self.elements[i],
-                        pre_round_key,
-                        post_round_key,
-                    )?;
-                }
+            if self.elements[i].is_constant::<CS>() {
+                // S-boxes of constants (like the arity tag, in the first round) can be computed statically, as constants.
+                self.elements[i] = constant_quintic_s_box::<CS, E>(
+                    &self.elements[i],
+                    pre_round_key,
+                    post_round_key,
+                );
+            } else if first_round {
+                self.elements[i] = quintic_s_box_pre_add(
+                    cs.namespace(|| format!("quintic s-box {}", i)),
+                    &self.elements[i],
+                    pre_round_key,
+                    post_round_key,
+                )?;
             } else {
-                self.elements[i] = quintic_s_box(
+                self.elements[i] = self.s_box(
                     cs.namespace(|| format!("quintic s-box {}", i)),
                     &self.elements[i],
                     post_round_key,
@@ -236,21 +316,58 @@ where
 
         // Multiply the elements by the constant MDS matrix
         self.product_mds::<CS>()?;
-        Ok(())
+        self.allocate_round_result(&mut cs)
     }
 
     fn partial_round<CS: ConstraintSystem<E>>(&mut self, mut cs: CS) -> Result<(), SynthesisError> {
         let round_key = self.constants.compressed_round_constants[self.constants_offset];
         self.constants_offset += 1;
         // Apply the quintic S-Box to the first element.
-        self.elements[0] = quintic_s_box(
-            cs.namespace(|| "solitary quintic s-box"),
-            &self.elements[0],
-            Some(round_key),
-        )?;
+        self.elements[0] = if self.elements[0].is_constant::<CS>() {
+            constant_quintic_s_box::<CS, E>(&self.elements[0], None, Some(round_key))
+        } else {
+            self.s_box(
+                cs.namespace(|| "solitary quintic s-box"),
+                &self.elements[0],
+                Some(round_key),
+            )?
+        };
 
         // Multiply the elements by the constant MDS matrix
         self.product_mds::<CS>()?;
+        self.allocate_round_result(&mut cs)
+    }
+
+    /// Apply the quintic s-box to `e`, according to the synthesis strategy.
+    fn s_box<CS: ConstraintSystem<E>>(
+        &self,
+        cs: CS,
+        e: &Elt<E>,
+        post_round_key: Option<E::Fr>,
+    ) -> Result<Elt<E>, SynthesisError> {
+        if self.strategy == SynthesisStrategy::FusedSBox && e.is_num() {
+            quintic_s_box_lc(cs, e, None, post_round_key)
+        } else {
+            quintic_s_box(cs, e, post_round_key)
+        }
+    }
+
+    /// Allocate the elements resulting from a round, if the synthesis strategy calls for it.
+    fn allocate_round_result<CS: ConstraintSystem<E>>(
+        &mut self,
+        cs: &mut CS,
+    ) -> Result<(), SynthesisError> {
+        if self.strategy == SynthesisStrategy::AllocateEveryRound {
+            for i in 0..self.elements.len() {
+                if self.elements[i].is_num() && !self.elements[i].is_constant::<CS>() {
+                    let allocated = self.elements[i].ensure_allocated(
+                        &mut cs.namespace(|| format!("round result {}", i)),
+                        true,
+                    )?;
+                    self.elements[i] = Elt::Allocated(allocated);
+                }
+            }
+        }
         Ok(())
     }
 
@@ -342,6 +459,41 @@ pub fn poseidon_hash<CS, E, A>(
     preimage: Vec<AllocatedNum<E>>,
     constants: &PoseidonConstants<E, A>,
 ) -> Result<AllocatedNum<E>, SynthesisError>
+where
+    CS: ConstraintSystem<E>,
+    E: Engine,
+    A: Arity<E::Fr>,
+{
+    poseidon_hash_elts(
+        cs,
+        preimage.into_iter().map(Elt::Allocated).collect(),
+        constants,
+    )
+}
+
+/// Create circuit for Poseidon hash of `preimage`, which may mix allocated numbers, linear combinations and
+/// constants. Linear combinations (including constants) are used directly by the first round's s-boxes, so need not
+/// be allocated first.
+pub fn poseidon_hash_elts<CS, E, A>(
+    cs: CS,
+    preimage: Vec<Elt<E>>,
+    constants: &PoseidonConstants<E, A>,
+) -> Result<AllocatedNum<E>, SynthesisError>
+where
+    CS: ConstraintSystem<E>,
+    E: Engine,
+    A: Arity<E::Fr>,
+{
+    poseidon_hash_with_strategy(cs, preimage, constants, SynthesisStrategy::Standard)
+}
+
+/// Create circuit for Poseidon hash of `preimage`, as by `poseidon_hash_elts`, synthesized according to `strategy`.
+pub fn poseidon_hash_with_strategy<CS, E, A>(
+    cs: CS,
+    preimage: Vec<Elt<E>>,
+    constants: &PoseidonConstants<E, A>,
+    strategy: SynthesisStrategy,
+) -> Result<AllocatedNum<E>, SynthesisError>
 where
     CS: ConstraintSystem<E>,
     E: Engine,
@@ -350,13 +502,259 @@ where
     let tag_element = Elt::num_from_fr::<CS>(constants.arity_tag);
     let mut elements = Vec::with_capacity(A::to_usize());
     elements.push(tag_element);
-    elements.extend(preimage.into_iter().map(Elt::Allocated));
+    elements.extend(preimage);
 
-    let mut p = PoseidonCircuit::new(elements, constants);
+    let mut p = PoseidonCircuit::new(elements, constants, strategy);
 
     p.hash(cs)
 }
 
+/// Create circuit for the Poseidon permutation of a full-width `state`, returning the whole permuted state. Unlike
+/// `poseidon_hash`, no arity tag is prepended and nothing is allocated for the result, so this can be used to build
+/// sponges and other modes needing more than one output. Returns `SynthesisError::Unsatisfiable` unless `state` has
+/// exactly `constants.width()` elements.
+pub fn poseidon_permutation<CS, E, A>(
+    mut cs: CS,
+    state: Vec<Elt<E>>,
+    constants: &PoseidonConstants<E, A>,
+) -> Result<Vec<Elt<E>>, SynthesisError>
+where
+    CS: ConstraintSystem<E>,
+    E: Engine,
+    A: Arity<E::Fr>,
+{
+    if state.len() != constants.width() {
+        return Err(SynthesisError::Unsatisfiable);
+    }
+
+    let mut p = PoseidonCircuit::new(state, constants, SynthesisStrategy::Standard);
+    p.permute(&mut cs)?;
+
+    Ok(p.elements)
+}
+
+/// Create circuit proving decryption of `ciphertext` (followed by its authentication tag) with `key` and `nonce`,
+/// as by `encryption::decrypt`, and return the message. The tag is enforced to match. Returns
+/// `SynthesisError::Unsatisfiable` if the arity is less than 3, or the ciphertext has no tag, as no decryption could
+/// satisfy the circuit.
+pub fn poseidon_decrypt<CS, E, A>(
+    mut cs: CS,
+    key: &[Elt<E>; 2],
+    nonce: Elt<E>,
+    ciphertext: &[Elt<E>],
+    constants: &PoseidonConstants<E, A>,
+) -> Result<Vec<Elt<E>>, SynthesisError>
+where
+    CS: ConstraintSystem<E>,
+    E: Engine,
+    A: Arity<E::Fr>,
+{
+    if constants.arity() < 3 {
+        return Err(SynthesisError::Unsatisfiable);
+    }
+    let (tag, ciphertext) = ciphertext
+        .split_last()
+        .ok_or_else(|| SynthesisError::Unsatisfiable)?;
+
+    let mut state = vec![Elt::num_from_fr::<CS>(E::Fr::zero()); constants.width()];
+    state[0] = Elt::num_from_fr::<CS>(encryption::domain_tag::<E>(ciphertext.len()));
+    state[1] = key[0].clone();
+    state[2] = key[1].clone();
+    state[3] = nonce;
+    state = poseidon_permutation(cs.namespace(|| "initial permutation"), state, constants)?;
+
+    let mut message = Vec::with_capacity(ciphertext.len());
+    for (i, chunk) in ciphertext.chunks(constants.arity()).enumerate() {
+        for (j, c) in chunk.iter().enumerate() {
+            message.push(c.clone().sub(state[j + 1].clone()));
+            state[j + 1] = c.clone();
+        }
+        state = poseidon_permutation(
+            cs.namespace(|| format!("permutation {}", i)),
+            state,
+            constants,
+        )?;
+    }
+
+    let (computed_tag, tag) = (state[1].lc(), tag.lc());
+    cs.enforce(
+        || "tag matches",
+        |lc| lc + &computed_tag - &tag,
+        |lc| lc + CS::one(),
+        |lc| lc,
+    );
+
+    Ok(message)
+}
+
+/// Create circuit for the hash of `bits`, as by `bytes::hash_bits`. The bits are packed into linear combinations, so
+/// packing costs no constraints. The bits must already be constrained to be boolean (as by `AllocatedBit`).
+pub fn poseidon_hash_bits<CS, E, A>(
+    mut cs: CS,
+    bits: &[Boolean],
+    constants: &PoseidonConstants<E, A>,
+) -> Result<AllocatedNum<E>, SynthesisError>
+where
+    CS: ConstraintSystem<E>,
+    E: Engine,
+    A: Arity<E::Fr>,
+{
+    let mut packed = vec![Elt::num_from_fr::<CS>(crate::scalar_from_u64::<E::Fr>(
+        bits.len() as u64,
+    ))];
+    for chunk in bits.chunks(bytes::PACKED_BITS) {
+        let mut num = num::Num::<E>::zero();
+        let mut coeff = E::Fr::one();
+        for bit in chunk {
+            num = num.add_bool_with_coeff(CS::one(), bit, coeff);
+            coeff.double();
+        }
+        packed.push(Elt::Num(num));
+    }
+
+    let mut state = vec![Elt::num_from_fr::<CS>(E::Fr::zero()); constants.width()];
+    state[0] = Elt::num_from_fr::<CS>(bytes::domain_tag::<E>());
+
+    for (i, chunk) in packed.chunks(constants.arity()).enumerate() {
+        for (j, element) in chunk.iter().enumerate() {
+            state[j + 1] = Elt::Num(
+                state[j + 1]
+                    .clone()
+                    .into_num()
+                    .add(&element.clone().into_num()),
+            );
+        }
+        state = poseidon_permutation(
+            cs.namespace(|| format!("permutation {}", i)),
+            state,
+            constants,
+        )?;
+    }
+
+    state[1].ensure_allocated(&mut cs.namespace(|| "hash result"), true)
+}
+
+/// Circuit for a Fiat–Shamir transcript, producing the same challenges as `transcript::Transcript` given the same
+/// operations. Labels and message lengths are constants, so cost no constraints to absorb.
+pub struct TranscriptCircuit<'a, E, A>
+where
+    E: Engine,
+    A: Arity<E::Fr>,
+{
+    state: Vec<Elt<E>>,
+    /// Number of elements absorbed into the rate since the last permutation.
+    absorbed: usize,
+    /// Number of permutations so far, to name their namespaces.
+    permutations: usize,
+    constants: &'a PoseidonConstants<E, A>,
+}
+
+impl<'a, E, A> TranscriptCircuit<'a, E, A>
+where
+    E: Engine,
+    A: Arity<E::Fr>,
+{
+    /// Create a transcript for the protocol identified by `label`.
+    pub fn new<CS: ConstraintSystem<E>>(
+        constants: &'a PoseidonConstants<E, A>,
+        label: &[u8],
+    ) -> Self {
+        let mut state = vec![Elt::num_from_fr::<CS>(E::Fr::zero()); constants.width()];
+        state[0] = Elt::num_from_fr::<CS>(transcript::domain_tag::<E>());
+
+        let mut transcript = Self {
+            state,
+            absorbed: 0,
+            permutations: 0,
+            constants,
+        };
+        transcript.absorb(Elt::num_from_fr::<CS>(transcript::label_to_fr::<E>(label)));
+        transcript
+    }
+
+    /// Absorb `element`.
+    pub fn append<CS: ConstraintSystem<E>>(
+        &mut self,
+        cs: CS,
+        element: &AllocatedNum<E>,
+    ) -> Result<(), SynthesisError> {
+        self.permute_if_full(cs)?;
+        self.absorb(Elt::Allocated(element.clone()));
+        Ok(())
+    }
+
+    /// Absorb `message`, with its `label` and length.
+    pub fn append_message<CS: ConstraintSystem<E>>(
+        &mut self,
+        mut cs: CS,
+        label: &[u8],
+        message: &[AllocatedNum<E>],
+    ) -> Result<(), SynthesisError> {
+        self.append_constant(
+            cs.namespace(|| "label"),
+            transcript::label_to_fr::<E>(label),
+        )?;
+        self.append_constant(
+            cs.namespace(|| "length"),
+            crate::scalar_from_u64::<E::Fr>(message.len() as u64),
+        )?;
+        for (i, element) in message.iter().enumerate() {
+            self.append(cs.namespace(|| format!("element {}", i)), element)?;
+        }
+        Ok(())
+    }
+
+    /// Absorb `label`, then squeeze a challenge.
+    pub fn challenge<CS: ConstraintSystem<E>>(
+        &mut self,
+        mut cs: CS,
+        label: &[u8],
+    ) -> Result<AllocatedNum<E>, SynthesisError> {
+        self.append_constant(
+            cs.namespace(|| "label"),
+            transcript::label_to_fr::<E>(label),
+        )?;
+        self.permute(cs.namespace(|| "squeeze"))?;
+
+        self.state[1].ensure_allocated(&mut cs.namespace(|| "challenge"), true)
+    }
+
+    fn append_constant<CS: ConstraintSystem<E>>(
+        &mut self,
+        cs: CS,
+        element: E::Fr,
+    ) -> Result<(), SynthesisError> {
+        self.permute_if_full(cs)?;
+        self.absorb(Elt::num_from_fr::<CS>(element));
+        Ok(())
+    }
+
+    fn permute_if_full<CS: ConstraintSystem<E>>(&mut self, cs: CS) -> Result<(), SynthesisError> {
+        if self.absorbed == self.constants.arity() {
+            self.permute(cs)?;
+        }
+        Ok(())
+    }
+
+    fn absorb(&mut self, element: Elt<E>) {
+        let i = self.absorbed + 1;
+        self.state[i] = Elt::Num(self.state[i].clone().into_num().add(&element.into_num()));
+        self.absorbed += 1;
+    }
+
+    fn permute<CS: ConstraintSystem<E>>(&mut self, mut cs: CS) -> Result<(), SynthesisError> {
+        let state = std::mem::replace(&mut self.state, Vec::new());
+        self.state = poseidon_permutation(
+            cs.namespace(|| format!("permutation {}", self.permutations)),
+            state,
+            self.constants,
+        )?;
+        self.permutations += 1;
+        self.absorbed = 0;
+        Ok(())
+    }
+}
+
 /// Compute l^5 and enforce constraint. If round_key is supplied, add it to result.
 fn quintic_s_box<CS: ConstraintSystem<E>, E: Engine>(
     mut cs: CS,
@@ -388,6 +786,9 @@ fn quintic_s_box_pre_add<CS: ConstraintSystem<E>, E: Engine>(
     post_round_key: Option<E::Fr>,
 ) -> Result<Elt<E>, SynthesisError> {
     if let (Some(pre_round_key), Some(post_round_key)) = (pre_round_key, post_round_key) {
+        if e.is_num() {
+            return quintic_s_box_lc(cs, e, Some(pre_round_key), Some(post_round_key));
+        }
         let l = e.ensure_allocated(&mut cs.namespace(|| "S-box input"), true)?;
 
         // If round_key was supplied, add it to l before squaring.
@@ -408,19 +809,80 @@ fn quintic_s_box_pre_add<CS: ConstraintSystem<E>, E: Engine>(
     }
 }
 
-/// Compute l^5 and enforce constraint. If round_key is supplied, add it to l first.
-fn constant_quintic_s_box_pre_add_tag<CS: ConstraintSystem<E>, E: Engine>(
-    tag: &Elt<E>,
+/// Compute (l + pre_round_key)^5 + post_round_key and enforce constraint, where l is a linear combination. The
+/// linear combination is used directly in the constraints, so (unlike `quintic_s_box` and `quintic_s_box_pre_add`)
+/// it need not be allocated first.
+fn quintic_s_box_lc<CS: ConstraintSystem<E>, E: Engine>(
+    mut cs: CS,
+    e: &Elt<E>,
+    pre_round_key: Option<E::Fr>,
+    post_round_key: Option<E::Fr>,
+) -> Result<Elt<E>, SynthesisError> {
+    let input = e.lc();
+    let input_plus_rk = |lc: LinearCombination<E>| match pre_round_key {
+        Some(rk) => lc + &input + (rk, CS::one()),
+        None => lc + &input,
+    };
+    let l_plus_rk = e.val().map(|mut l| {
+        if let Some(rk) = pre_round_key {
+            l.add_assign(&rk);
+        }
+        l
+    });
+
+    let l2 = AllocatedNum::alloc(cs.namespace(|| "(l+rk)^2"), || {
+        let mut tmp = l_plus_rk.ok_or_else(|| SynthesisError::AssignmentMissing)?;
+        tmp.square();
+        Ok(tmp)
+    })?;
+    cs.enforce(
+        || "squared sum constraint",
+        |lc| input_plus_rk(lc),
+        |lc| input_plus_rk(lc),
+        |lc| lc + l2.get_variable(),
+    );
+
+    let l4 = l2.square(cs.namespace(|| "l^4"))?;
+
+    let l5 = AllocatedNum::alloc(cs.namespace(|| "l4 * (l + rk)"), || {
+        let mut tmp = l_plus_rk.ok_or_else(|| SynthesisError::AssignmentMissing)?;
+        tmp.mul_assign(
+            &l4.get_value()
+                .ok_or_else(|| SynthesisError::AssignmentMissing)?,
+        );
+        if let Some(rk) = post_round_key {
+            tmp.add_assign(&rk);
+        }
+        Ok(tmp)
+    })?;
+    cs.enforce(
+        || "mul sum constraint",
+        |lc| input_plus_rk(lc),
+        |lc| lc + l4.get_variable(),
+        |lc| match post_round_key {
+            Some(rk) => {
+                let mut neg = E::Fr::zero();
+                neg.sub_assign(&rk);
+                lc + l5.get_variable() + (neg, CS::one())
+            }
+            None => lc + l5.get_variable(),
+        },
+    );
+
+    Ok(Elt::Allocated(l5))
+}
+
+/// Compute (l + pre_round_key)^5 + post_round_key statically, for a constant l. No constraints are needed.
+fn constant_quintic_s_box<CS: ConstraintSystem<E>, E: Engine>(
+    e: &Elt<E>,
     pre_round_key: Option<E::Fr>,
     post_round_key: Option<E::Fr>,
 ) -> Elt<E> {
-    let mut tag = tag.val().expect("missing tag val");
-    pre_round_key.expect("pre_round_key must be provided");
-    post_round_key.expect("post_round_key must be provided");
+    let mut l = e.val().expect("missing constant val");
 
-    crate::quintic_s_box::<E>(&mut tag, pre_round_key.as_ref(), post_round_key.as_ref());
+    crate::quintic_s_box::<E>(&mut l, pre_round_key.as_ref(), post_round_key.as_ref());
 
-    Elt::num_from_fr::<CS>(tag)
+    Elt::num_from_fr::<CS>(l)
 }
 
 /// Calculates square of sum and enforces that constraint.
@@ -586,7 +1048,9 @@ fn scalar_product<E: Engine, CS: ConstraintSystem<E>>(
 mod tests {
     use super::*;
     use crate::poseidon::HashMode;
+    use crate::poseidon_alt::hash_correct;
     use crate::{scalar_from_u64, Poseidon, Strength};
+    use bellperson::gadgets::boolean::AllocatedBit;
     use bellperson::util_cs::test_cs::TestConstraintSystem;
     use bellperson::ConstraintSystem;
     use generic_array::typenum;
@@ -822,4 +1286,302 @@ mod tests {
         assert!(res.is_num());
         assert_eq!(fr(59), res.val().unwrap());
     }
+
+    #[test]
+    fn test_poseidon_hash_elts() {
+        let mut rng = XorShiftRng::from_seed(crate::TEST_SEED);
+        let mut cs = TestConstraintSystem::<Bls12>::new();
+        let constants = PoseidonConstants::<Bls12, typenum::U4>::new();
+
+        let a = Fr::random(&mut rng);
+        let b = Fr::random(&mut rng);
+        let c = Fr::random(&mut rng);
+        let na = AllocatedNum::alloc(cs.namespace(|| "a"), || Ok(a)).unwrap();
+        let nb = AllocatedNum::alloc(cs.namespace(|| "b"), || Ok(b)).unwrap();
+        let nc = AllocatedNum::alloc(cs.namespace(|| "c"), || Ok(c)).unwrap();
+
+        // a + 2b
+        let sum = num::Num::from(na.clone()).add(&num::Num::from(nb).scale(fr(2)));
+        let mut sum_value = b;
+        sum_value.double();
+        sum_value.add_assign(&a);
+
+        let preimage = vec![efr(7), Elt::from(sum), Elt::from(nc), Elt::from(na)];
+        let out = poseidon_hash_elts(&mut cs, preimage, &constants).unwrap();
+
+        let mut p = Poseidon::<Bls12, typenum::U4>::new_with_preimage(
+            &[fr(7), sum_value, c, a],
+            &constants,
+        );
+        let expected: Fr = p.hash_in_mode(HashMode::Correct);
+
+        assert!(cs.is_satisfied(), "constraints not satisfied");
+        assert_eq!(expected, out.get_value().unwrap());
+        // The linear combination costs no more than an allocated input, and the constant saves its first s-box.
+        assert_eq!(377 - 3, cs.num_constraints());
+    }
+
+    #[test]
+    fn test_poseidon_hash_constant_inputs() {
+        // Each constant input saves the three constraints of its first s-box.
+        test_poseidon_hash_constant_inputs_aux(1, 377 - 3);
+        test_poseidon_hash_constant_inputs_aux(2, 377 - 6);
+        test_poseidon_hash_constant_inputs_aux(3, 377 - 9);
+        // When every input is constant, the whole hash is, and only allocating the result is constrained.
+        test_poseidon_hash_constant_inputs_aux(4, 1);
+    }
+
+    fn test_poseidon_hash_constant_inputs_aux(constant_count: usize, expected_constraints: usize) {
+        let mut rng = XorShiftRng::from_seed(crate::TEST_SEED);
+        let mut cs = TestConstraintSystem::<Bls12>::new();
+        let constants = PoseidonConstants::<Bls12, typenum::U4>::new();
+
+        let fr_data: Vec<Fr> = (0..4).map(|_| Fr::random(&mut rng)).collect();
+        let preimage = fr_data
+            .iter()
+            .enumerate()
+            .map(|(i, fr)| {
+                if i < constant_count {
+                    Elt::num_from_fr::<TestConstraintSystem<Bls12>>(*fr)
+                } else {
+                    let n = AllocatedNum::alloc(cs.namespace(|| format!("data {}", i)), || Ok(*fr))
+                        .unwrap();
+                    Elt::Allocated(n)
+                }
+            })
+            .collect();
+
+        let out = poseidon_hash_elts(&mut cs, preimage, &constants).unwrap();
+
+        let mut p = Poseidon::<Bls12, typenum::U4>::new_with_preimage(&fr_data, &constants);
+        let expected: Fr = p.hash_in_mode(HashMode::Correct);
+
+        assert!(cs.is_satisfied(), "constraints not satisfied");
+        assert_eq!(expected, out.get_value().unwrap());
+        assert_eq!(expected_constraints, cs.num_constraints());
+    }
+
+    #[test]
+    fn test_poseidon_permutation() {
+        let mut rng = XorShiftRng::from_seed(crate::TEST_SEED);
+        let mut cs = TestConstraintSystem::<Bls12>::new();
+        let constants = PoseidonConstants::<Bls12, typenum::U4>::new();
+        let width = constants.width();
+
+        let fr_state: Vec<Fr> = (0..width).map(|_| Fr::random(&mut rng)).collect();
+        let state = fr_state
+            .iter()
+            .enumerate()
+            .map(|(i, fr)| {
+                let n = AllocatedNum::alloc(cs.namespace(|| format!("state {}", i)), || Ok(*fr))
+                    .unwrap();
+                Elt::Allocated(n)
+            })
+            .collect();
+
+        let out = poseidon_permutation(&mut cs, state, &constants).unwrap();
+
+        let mut p = Poseidon::<Bls12, typenum::U4>::new(&constants);
+        p.elements.copy_from_slice(&fr_state);
+        hash_correct(&mut p);
+
+        assert!(cs.is_satisfied(), "constraints not satisfied");
+        assert_eq!(
+            p.elements.to_vec(),
+            out.iter().map(|elt| elt.val().unwrap()).collect::<Vec<_>>()
+        );
+        // As for hashing, except that the first element is not a constant tag and no result is allocated.
+        assert_eq!(377 + 3 - 1, cs.num_constraints());
+    }
+
+    #[test]
+    fn test_poseidon_permutation_invalid() {
+        let mut cs = TestConstraintSystem::<Bls12>::new();
+        let constants = PoseidonConstants::<Bls12, typenum::U4>::new();
+        let elt = Elt::num_from_fr::<TestConstraintSystem<Bls12>>(Fr::one());
+
+        // A state of the wrong width is an error rather than a panic.
+        for len in &[constants.width() - 1, constants.width() + 1] {
+            let state = vec![elt.clone(); *len];
+            assert!(poseidon_permutation(&mut cs, state, &constants).is_err());
+        }
+    }
+
+    #[test]
+    fn test_poseidon_decrypt() {
+        test_poseidon_decrypt_aux(7, false);
+        test_poseidon_decrypt_aux(7, true);
+    }
+
+    fn test_poseidon_decrypt_aux(len: usize, tamper: bool) {
+        let mut rng = XorShiftRng::from_seed(crate::TEST_SEED);
+        let mut cs = TestConstraintSystem::<Bls12>::new();
+        let constants = PoseidonConstants::<Bls12, typenum::U4>::new();
+
+        let key = [Fr::random(&mut rng), Fr::random(&mut rng)];
+        let nonce = Fr::random(&mut rng);
+        let message: Vec<Fr> = (0..len).map(|_| Fr::random(&mut rng)).collect();
+        let mut ciphertext = encryption::encrypt(&constants, &key, nonce, &message).unwrap();
+        if tamper {
+            ciphertext[2].add_assign(&Fr::one());
+        }
+
+        let mut alloc = |name: &str, fr: Fr| {
+            Elt::Allocated(AllocatedNum::alloc(cs.namespace(|| name), || Ok(fr)).unwrap())
+        };
+        let key_elts = [alloc("k0", key[0]), alloc("k1", key[1])];
+        let nonce_elt = alloc("nonce", nonce);
+        let ciphertext_elts: Vec<_> = ciphertext
+            .iter()
+            .enumerate()
+            .map(|(i, c)| alloc(&format!("ciphertext {}", i), *c))
+            .collect();
+
+        let out =
+            poseidon_decrypt(&mut cs, &key_elts, nonce_elt, &ciphertext_elts, &constants).unwrap();
+
+        assert_eq!(!tamper, cs.is_satisfied());
+        if !tamper {
+            assert_eq!(
+                message,
+                out.iter().map(|elt| elt.val().unwrap()).collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn test_poseidon_decrypt_invalid() {
+        let mut cs = TestConstraintSystem::<Bls12>::new();
+        let key = [
+            Elt::num_from_fr::<TestConstraintSystem<Bls12>>(Fr::one()),
+            Elt::num_from_fr::<TestConstraintSystem<Bls12>>(Fr::one()),
+        ];
+        let nonce = Elt::num_from_fr::<TestConstraintSystem<Bls12>>(Fr::one());
+
+        // Too small an arity, or a ciphertext without a tag, is an error rather than a panic.
+        let constants = PoseidonConstants::<Bls12, typenum::U2>::new();
+        let ciphertext = vec![nonce.clone(); 2];
+        assert!(poseidon_decrypt(&mut cs, &key, nonce.clone(), &ciphertext, &constants).is_err());
+
+        let constants = PoseidonConstants::<Bls12, typenum::U4>::new();
+        assert!(poseidon_decrypt(&mut cs, &key, nonce, &[], &constants).is_err());
+    }
+
+    #[test]
+    fn test_transcript_circuit() {
+        let mut rng = XorShiftRng::from_seed(crate::TEST_SEED);
+        let mut cs = TestConstraintSystem::<Bls12>::new();
+        let constants = PoseidonConstants::<Bls12, typenum::U4>::new();
+
+        let message: Vec<Fr> = (0..6).map(|_| Fr::random(&mut rng)).collect();
+        let element = Fr::random(&mut rng);
+
+        let mut native = transcript::Transcript::new(&constants, b"test protocol");
+        native.append_message(b"message", &message);
+        let expected_first = native.challenge(b"first");
+        native.append(element);
+        let expected_second = native.challenge(b"second");
+
+        let allocated_message: Vec<_> = message
+            .iter()
+            .enumerate()
+            .map(|(i, fr)| {
+                AllocatedNum::alloc(cs.namespace(|| format!("message {}", i)), || Ok(*fr)).unwrap()
+            })
+            .collect();
+        let allocated_element =
+            AllocatedNum::alloc(cs.namespace(|| "allocated element"), || Ok(element)).unwrap();
+
+        let mut transcript =
+            TranscriptCircuit::new::<TestConstraintSystem<Bls12>>(&constants, b"test protocol");
+        transcript
+            .append_message(cs.namespace(|| "message"), b"message", &allocated_message)
+            .unwrap();
+        let first = transcript
+            .challenge(cs.namespace(|| "first"), b"first")
+            .unwrap();
+        transcript
+            .append(cs.namespace(|| "element"), &allocated_element)
+            .unwrap();
+        let second = transcript
+            .challenge(cs.namespace(|| "second"), b"second")
+            .unwrap();
+
+        assert!(cs.is_satisfied(), "constraints not satisfied");
+        assert_eq!(expected_first, first.get_value().unwrap());
+        assert_eq!(expected_second, second.get_value().unwrap());
+    }
+
+    #[test]
+    fn test_poseidon_hash_bits() {
+        let mut cs = TestConstraintSystem::<Bls12>::new();
+        let constants = PoseidonConstants::<Bls12, typenum::U4>::new();
+
+        // 800 bits pack into four elements, plus the length, so need two permutations.
+        let data: Vec<u8> = (0..100).map(|i| (i * 7) as u8).collect();
+        let bits = bytes::bytes_to_bits_le(&data);
+        let allocated_bits: Vec<Boolean> = bits
+            .iter()
+            .enumerate()
+            .map(|(i, bit)| {
+                Boolean::from(
+                    AllocatedBit::alloc(cs.namespace(|| format!("bit {}", i)), Some(*bit)).unwrap(),
+                )
+            })
+            .collect();
+        let bit_constraints = cs.num_constraints();
+
+        let out = poseidon_hash_bits(&mut cs, &allocated_bits, &constants).unwrap();
+
+        assert!(cs.is_satisfied(), "constraints not satisfied");
+        assert_eq!(
+            bytes::hash_bytes(&constants, &data),
+            out.get_value().unwrap()
+        );
+        // Packing is free: only the permutations and result are constrained. In the first permutation, the domain tag
+        // and length are constants, so their first s-boxes are free.
+        let permutation_constraints = 377 + 3 - 1;
+        assert_eq!(
+            bit_constraints + (permutation_constraints - 2 * 3) + permutation_constraints + 1,
+            cs.num_constraints()
+        );
+    }
+
+    #[test]
+    fn test_synthesis_strategies() {
+        // Standard is as in `test_poseidon_hash`. Fusing s-boxes saves the allocation at each (non-first round) s-box
+        // input, leaving 285 s-box constraints and the allocation of the result. Allocating every round also leaves
+        // 285 s-box constraints, since every s-box input is already allocated, and adds one allocation per element per
+        // round (8 full and 56 partial), which leaves the result allocated.
+        test_synthesis_strategy_aux(SynthesisStrategy::Standard, 377);
+        test_synthesis_strategy_aux(SynthesisStrategy::AllocateEveryRound, 285 + 5 * (8 + 56));
+        test_synthesis_strategy_aux(SynthesisStrategy::FusedSBox, 285 + 1);
+    }
+
+    fn test_synthesis_strategy_aux(strategy: SynthesisStrategy, expected_constraints: usize) {
+        let mut rng = XorShiftRng::from_seed(crate::TEST_SEED);
+        let mut cs = TestConstraintSystem::<Bls12>::new();
+        let constants = PoseidonConstants::<Bls12, typenum::U4>::new();
+
+        let fr_data: Vec<Fr> = (0..4).map(|_| Fr::random(&mut rng)).collect();
+        let preimage = fr_data
+            .iter()
+            .enumerate()
+            .map(|(i, fr)| {
+                Elt::Allocated(
+                    AllocatedNum::alloc(cs.namespace(|| format!("data {}", i)), || Ok(*fr))
+                        .unwrap(),
+                )
+            })
+            .collect();
+
+        let out = poseidon_hash_with_strategy(&mut cs, preimage, &constants, strategy).unwrap();
+
+        let mut p = Poseidon::<Bls12, typenum::U4>::new_with_preimage(&fr_data, &constants);
+        let expected: Fr = p.hash_in_mode(HashMode::Correct);
+
+        assert!(cs.is_satisfied(), "constraints not satisfied");
+        assert_eq!(expected, out.get_value().unwrap());
+        assert_eq!(expected_constraints, cs.num_constraints());
+    }
 }
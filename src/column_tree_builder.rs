@@ -1,12 +1,16 @@
-use crate::batch_hasher::{Batcher, BatcherType};
+use crate::backend::BackendFactory;
+use crate::batch_hasher::{Batcher, BatcherConfig, BatcherType};
+use crate::checkpoint::Checkpoint;
 use crate::error::Error;
 use crate::poseidon::{Poseidon, PoseidonConstants};
+use crate::progress::{BuildPhase, CancellationToken, ProgressObserver, ProgressTracker};
 use crate::tree_builder::{TreeBuilder, TreeBuilderTrait};
-use crate::{Arity, BatchHasher};
+use crate::{Arity, BatchHasher, DEFAULT_STRENGTH};
 use ff::Field;
 use generic_array::GenericArray;
 use paired::bls12_381::{Bls12, Fr};
-use log::info;
+use log::{info, warn};
+use std::sync::Arc;
 
 pub trait ColumnTreeBuilderTrait<ColumnArity, TreeArity>
 where
@@ -33,7 +37,15 @@ where
     fill_index: usize,
     column_constants: PoseidonConstants<Bls12, ColumnArity>,
     pub column_batcher: Option<Batcher<'a, ColumnArity>>,
+    t: Option<BatcherType>,
+    column_backend: Option<BackendFactory<ColumnArity>>,
+    column_config: BatcherConfig,
     tree_builder: TreeBuilder<'a, TreeArity>,
+    rows_to_discard: usize,
+    observer: Option<Arc<dyn ProgressObserver>>,
+    cancellation: Option<CancellationToken>,
+    /// Tracks progress of hashing columns, from the first column added until reset.
+    column_progress: Option<ProgressTracker>,
 }
 
 impl<ColumnArity, TreeArity> ColumnTreeBuilderTrait<ColumnArity, TreeArity>
@@ -51,9 +63,33 @@ where
             return Err(Error::Other("too many columns".to_string()));
         }
 
+        let (observer, cancellation, leaf_count) =
+            (&self.observer, &self.cancellation, self.leaf_count);
+        let progress = self.column_progress.get_or_insert_with(|| {
+            ProgressTracker::new(
+                BuildPhase::Columns,
+                observer.clone(),
+                cancellation.clone(),
+                1,
+                leaf_count,
+            )
+        });
+        if let Err(e) = progress.check_cancelled() {
+            return Err(self.abandon(e));
+        }
+
+        // The column batcher is released before each tree is built, so is created again for the next one.
+        if self.column_batcher.is_none() {
+            self.column_batcher = self.new_column_batcher()?;
+        }
+
         match self.column_batcher {
             Some(ref mut batcher) => {
-                batcher.hash_into_slice(&mut self.data[start..start + column_count], columns)?;
+                let hashed =
+                    batcher.hash_into_slice(&mut self.data[start..start + column_count], columns);
+                if let Err(e) = hashed {
+                    return Err(self.abandon(e));
+                }
             }
             None => columns.iter().enumerate().for_each(|(i, column)| {
                 self.data[start + i] =
@@ -63,6 +99,10 @@ where
 
         self.fill_index += column_count;
 
+        if let Some(progress) = &mut self.column_progress {
+            progress.batch_done(column_count, end == self.leaf_count);
+        }
+
         Ok(())
     }
 
@@ -72,10 +112,8 @@ where
     ) -> Result<(Vec<Fr>, Vec<Fr>), Error> {
         self.add_columns(columns)?;
 
-        if let Some(_) = self.column_batcher {
-            info!("ColumnTreeBuilder.add_final_columns drop column_batcher early");
-            self.column_batcher.take();
-        }
+        info!("ColumnTreeBuilder.add_final_columns release column_batcher early");
+        self.release_column_batcher()?;
 
         let (base, tree) = self.tree_builder.add_final_leaves(&self.data)?;
         self.reset();
@@ -85,6 +123,7 @@ where
 
     fn reset(&mut self) {
         self.fill_index = 0;
+        self.column_progress = None;
         self.data.iter_mut().for_each(|place| *place = Fr::zero());
     }
 }
@@ -106,35 +145,155 @@ fn as_generic_arrays<'a, A: Arity<Fr>>(vec: &'a [Fr]) -> &'a [GenericArray<Fr, A
     }
 }
 
-impl<ColumnArity, TreeArity> ColumnTreeBuilder<'_, ColumnArity, TreeArity>
+impl<'a, ColumnArity, TreeArity> ColumnTreeBuilder<'a, ColumnArity, TreeArity>
 where
     ColumnArity: Arity<Fr>,
     TreeArity: Arity<Fr>,
 {
+    /// Create a builder for `leaf_count` columns, hashed by batchers of type `t` configured by `column_config`, and
+    /// then a tree of their hashes, hashed by batchers configured by `tree_config`.
     pub fn new(
         t: Option<BatcherType>,
         leaf_count: usize,
-        max_column_batch_size: usize,
-        max_tree_batch_size: usize,
+        column_config: BatcherConfig,
+        tree_config: BatcherConfig,
+        rows_to_discard: usize,
+    ) -> Result<Self, Error> {
+        Self::create(
+            t,
+            None,
+            None,
+            leaf_count,
+            column_config,
+            tree_config,
+            rows_to_discard,
+        )
+    }
+
+    /// Create a builder as by `new`, hashing columns and tree with `BatcherType::Custom` batchers on the backends
+    /// created by `column_backend` and `tree_backend` respectively.
+    pub fn with_backends(
+        column_backend: BackendFactory<ColumnArity>,
+        tree_backend: BackendFactory<TreeArity>,
+        leaf_count: usize,
+        column_config: BatcherConfig,
+        tree_config: BatcherConfig,
+        rows_to_discard: usize,
+    ) -> Result<Self, Error> {
+        Self::create(
+            Some(BatcherType::Custom),
+            Some(column_backend),
+            Some(tree_backend),
+            leaf_count,
+            column_config,
+            tree_config,
+            rows_to_discard,
+        )
+    }
+
+    fn create(
+        t: Option<BatcherType>,
+        column_backend: Option<BackendFactory<ColumnArity>>,
+        tree_backend: Option<BackendFactory<TreeArity>>,
+        leaf_count: usize,
+        column_config: BatcherConfig,
+        tree_config: BatcherConfig,
+        rows_to_discard: usize,
     ) -> Result<Self, Error> {
-        let builder = Self {
+        column_config.validate()?;
+
+        let mut builder = Self {
             leaf_count,
             data: vec![Fr::zero(); leaf_count],
             fill_index: 0,
             column_constants: PoseidonConstants::<Bls12, ColumnArity>::new(),
-            column_batcher: if let Some(t) = &t {
-                Some(Batcher::<ColumnArity>::new(t, max_column_batch_size)?)
-            } else {
-                None
-            },
-            tree_builder: TreeBuilder::<TreeArity>::new(t, leaf_count, max_tree_batch_size, 0)?,
+            column_batcher: None,
+            t,
+            column_backend,
+            column_config,
+            tree_builder: TreeBuilder::<TreeArity>::create(
+                t,
+                tree_backend,
+                leaf_count,
+                tree_config,
+                rows_to_discard,
+            )?,
+            rows_to_discard,
+            observer: None,
+            cancellation: None,
+            column_progress: None,
         };
+        builder.column_batcher = builder.new_column_batcher()?;
 
         Ok(builder)
     }
 
+    /// Create a column batcher of the builder's type, if it has one.
+    fn new_column_batcher(&self) -> Result<Option<Batcher<'a, ColumnArity>>, Error> {
+        match &self.t {
+            Some(t) => Ok(Some(Batcher::with_factory(
+                DEFAULT_STRENGTH,
+                t,
+                &self.column_config,
+                self.column_backend.as_ref(),
+            )?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Release the column and tree batchers, if there are any. The column batcher is kept between batches of
+    /// columns, and released before the tree is built or when a batch fails or is cancelled, so this is only needed
+    /// to abandon a build part way. See `TreeBuilder::release`.
+    pub fn release(&mut self) -> Result<(), Error> {
+        let released = self.release_column_batcher();
+        self.tree_builder.release()?;
+        released
+    }
+
+    /// Release the column batcher after `error` failed the build, returning `error`. A later batch creates a new
+    /// batcher.
+    fn abandon(&mut self, error: Error) -> Error {
+        if let Err(e) = self.release_column_batcher() {
+            warn!("Failed to release column batcher: {}", e);
+        }
+        error
+    }
+
+    fn release_column_batcher(&mut self) -> Result<(), Error> {
+        match self.column_batcher.take() {
+            Some(mut batcher) => batcher.release(),
+            None => Ok(()),
+        }
+    }
+
+    /// Report progress of hashing columns, then of building the tree, to `observer`.
+    pub fn set_progress_observer(&mut self, observer: Arc<dyn ProgressObserver>) {
+        self.tree_builder.set_progress_observer(observer.clone());
+        self.observer = Some(observer);
+    }
+
+    /// Check `token` before hashing each batch of columns, and between batches of the tree build.
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.tree_builder.set_cancellation_token(token.clone());
+        self.cancellation = Some(token);
+    }
+
+    /// Checkpoint the rows of the tree as they are built. See `TreeBuilder::set_checkpoint`.
+    pub fn set_checkpoint(&mut self, checkpoint: Checkpoint) {
+        self.tree_builder.set_checkpoint(checkpoint);
+    }
+
+    /// `tree_size` returns the number of nodes in the tree to cache.
+    /// This excludes the base row (of column hashes) and the following `rows_to_discard` rows.
     pub fn tree_size(&self) -> usize {
-        self.tree_builder.tree_size(0)
+        self.tree_builder.tree_size(self.rows_to_discard)
+    }
+
+    /// Reconstruct the discarded rows of a tree built by this builder, from its base row of column hashes.
+    /// See `TreeBuilder::reconstruct_discarded_rows`.
+    pub fn reconstruct_discarded_rows(&mut self, base: &[Fr]) -> Result<Vec<Fr>, Error> {
+        self.tree_builder
+            .reconstruct_discarded_rows(base, self.rows_to_discard)
     }
 
     // Compute root of tree composed of all identical columns. For use in checking correctness of GPU column tree-building
@@ -153,6 +312,7 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fake_device::{fake_backend_factory, fake_backends, FakeOperation};
     use crate::poseidon::Poseidon;
     use crate::BatchHasher;
     use ff::Field;
@@ -165,6 +325,7 @@ mod tests {
         // 16KiB tree has 512 leaves.
         test_column_tree_builder_aux(None, 512, 32, 512, 512);
         test_column_tree_builder_aux(Some(BatcherType::CPU), 512, 32, 512, 512);
+        test_column_tree_builder_aux(Some(BatcherType::Custom), 512, 32, 512, 512);
 
         #[cfg(all(feature = "gpu", not(target_os = "macos")))]
         test_column_tree_builder_aux(Some(BatcherType::GPU), 512, 32, 512, 512);
@@ -179,52 +340,113 @@ mod tests {
     ) {
         let batch_size = leaves / num_batches;
 
-        let mut builder = ColumnTreeBuilder::<U11, U8>::new(
-            batcher_type,
-            leaves,
-            max_column_batch_size,
-            max_tree_batch_size,
-        )
-        .unwrap();
+        for rows_to_discard in 0..3 {
+            let column_config = BatcherConfig::new(max_column_batch_size);
+            let tree_config = BatcherConfig::new(max_tree_batch_size);
+            let mut builder = match batcher_type {
+                Some(BatcherType::Custom) => {
+                    let (control, column_factory) = fake_backends();
+                    ColumnTreeBuilder::<U11, U8>::with_backends(
+                        column_factory,
+                        fake_backend_factory(&control),
+                        leaves,
+                        column_config,
+                        tree_config,
+                        rows_to_discard,
+                    )
+                }
+                _ => ColumnTreeBuilder::<U11, U8>::new(
+                    batcher_type,
+                    leaves,
+                    column_config,
+                    tree_config,
+                    rows_to_discard,
+                ),
+            }
+            .unwrap();
 
-        // Simplify computing the expected root.
-        let constant_element = Fr::zero();
-        let constant_column = GenericArray::<Fr, U11>::generate(|_| constant_element);
+            // Simplify computing the expected root.
+            let constant_element = Fr::zero();
+            let constant_column = GenericArray::<Fr, U11>::generate(|_| constant_element);
 
-        let max_batch_size = if let Some(batcher) = &builder.column_batcher {
-            batcher.max_batch_size()
-        } else {
-            leaves
-        };
+            let max_batch_size = if let Some(batcher) = &builder.column_batcher {
+                batcher.max_batch_size()
+            } else {
+                leaves
+            };
 
-        let effective_batch_size = usize::min(batch_size, max_batch_size);
+            let effective_batch_size = usize::min(batch_size, max_batch_size);
 
-        let mut total_columns = 0;
-        while total_columns + effective_batch_size < leaves {
-            let columns: Vec<GenericArray<Fr, U11>> =
-                (0..effective_batch_size).map(|_| constant_column).collect();
+            let mut total_columns = 0;
+            while total_columns + effective_batch_size < leaves {
+                let columns: Vec<GenericArray<Fr, U11>> =
+                    (0..effective_batch_size).map(|_| constant_column).collect();
 
-            let _ = builder.add_columns(columns.as_slice()).unwrap();
-            total_columns += columns.len();
-        }
+                let _ = builder.add_columns(columns.as_slice()).unwrap();
+                total_columns += columns.len();
+            }
+
+            let final_columns: Vec<_> = (0..leaves - total_columns)
+                .map(|_| GenericArray::<Fr, U11>::generate(|_| constant_element))
+                .collect();
+
+            let (base, res) = builder.add_final_columns(final_columns.as_slice()).unwrap();
+            // The column batcher is released before building the tree, and created again for the next one.
+            assert!(builder.column_batcher.is_none());
+
+            let column_hash =
+                Poseidon::new_with_preimage(&constant_column, &builder.column_constants).hash();
+            assert!(base.iter().all(|x| *x == column_hash));
+
+            let computed_root = res[res.len() - 1];
+
+            let expected_root = builder.compute_uniform_tree_root(final_columns[0]).unwrap();
+            let expected_size = builder.tree_builder.tree_size(rows_to_discard);
 
-        let final_columns: Vec<_> = (0..leaves - total_columns)
-            .map(|_| GenericArray::<Fr, U11>::generate(|_| constant_element))
-            .collect();
+            assert_eq!(leaves, base.len());
+            assert_eq!(expected_size, builder.tree_size());
+            assert_eq!(expected_size, res.len());
+            assert_eq!(expected_root, computed_root);
 
-        let (base, res) = builder.add_final_columns(final_columns.as_slice()).unwrap();
+            // Reconstructing the discarded rows restores the tree which would have been built without discarding.
+            let mut full_tree = builder.reconstruct_discarded_rows(&base).unwrap();
+            full_tree.extend_from_slice(&res);
+            assert_eq!(builder.tree_builder.tree_size(0), full_tree.len());
 
-        let column_hash =
-            Poseidon::new_with_preimage(&constant_column, &builder.column_constants).hash();
-        assert!(base.iter().all(|x| *x == column_hash));
+            let tree_constants = PoseidonConstants::<Bls12, U8>::new();
+            let first_row_hash =
+                Poseidon::new_with_preimage(&[column_hash; 8], &tree_constants).hash();
+            assert!(full_tree[..leaves / 8].iter().all(|x| *x == first_row_hash));
+        }
+    }
+
+    #[test]
+    fn test_column_tree_builder_release() {
+        let leaves = 64;
+        let (control, column_factory) = fake_backends();
+        let mut builder = ColumnTreeBuilder::<U11, U8>::with_backends(
+            column_factory,
+            fake_backend_factory(&control),
+            leaves,
+            BatcherConfig::new(leaves),
+            BatcherConfig::new(leaves),
+            0,
+        )
+        .unwrap();
+        let columns = vec![GenericArray::<Fr, U11>::generate(|_| Fr::one()); leaves];
 
-        let computed_root = res[res.len() - 1];
+        // The column batcher is created with the builder, and released before the tree batcher is created.
+        assert_eq!(1, control.calls(FakeOperation::Init));
+        builder.add_final_columns(&columns).unwrap();
+        control.assert_released(2);
 
-        let expected_root = builder.compute_uniform_tree_root(final_columns[0]).unwrap();
-        let expected_size = builder.tree_builder.tree_size(0);
+        // A failed batch releases the column batcher, and the next batch creates a new one.
+        control.fail_next(FakeOperation::Submit, 1, Error::DecodingError);
+        assert!(builder.add_columns(&columns[..8]).is_err());
+        assert!(builder.column_batcher.is_none());
+        assert_eq!(3, control.calls(FakeOperation::Release));
 
-        assert_eq!(leaves, base.len());
-        assert_eq!(expected_size, res.len());
-        assert_eq!(expected_root, computed_root);
+        builder.add_final_columns(&columns).unwrap();
+        control.assert_released(5);
     }
 }
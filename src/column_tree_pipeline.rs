@@ -0,0 +1,418 @@
+use crate::backend::BackendFactory;
+use crate::batch_hasher::{Batcher, BatcherConfig, BatcherType};
+use crate::checkpoint::Checkpoint;
+use crate::error::Error;
+use crate::poseidon::{Poseidon, PoseidonConstants};
+use crate::progress::{CancellationToken, ProgressObserver};
+use crate::tree_builder::{TreeBuilder, TreeBuilderTrait};
+use crate::{Arity, BatchHasher, DEFAULT_STRENGTH};
+use generic_array::GenericArray;
+use log::{info, warn};
+use paired::bls12_381::{Bls12, Fr};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// `ColumnTreePipeline` is an asynchronous variant of `ColumnTreeBuilder`. Batches of columns are enqueued and hashed
+/// on a worker thread (with its own GPU or CPU batcher), so the caller can prepare the next batch while the previous
+/// one is being hashed. At most `queue_depth` batches wait in the queue: beyond that, `add_columns` blocks until the
+/// worker catches up.
+pub struct ColumnTreePipeline<ColumnArity, TreeArity>
+where
+    ColumnArity: Arity<Fr>,
+    TreeArity: Arity<Fr>,
+{
+    pub leaf_count: usize,
+    /// Number of columns enqueued so far.
+    enqueued: usize,
+    sender: Option<SyncSender<Vec<GenericArray<Fr, ColumnArity>>>>,
+    worker: Option<JoinHandle<Result<Vec<Fr>, Error>>>,
+    tree_builder: TreeBuilder<'static, TreeArity>,
+    rows_to_discard: usize,
+    cancellation: Option<CancellationToken>,
+}
+
+impl<ColumnArity, TreeArity> ColumnTreePipeline<ColumnArity, TreeArity>
+where
+    ColumnArity: 'static + Arity<Fr>,
+    GenericArray<Fr, ColumnArity>: Send,
+    TreeArity: Arity<Fr>,
+{
+    pub fn new(
+        t: Option<BatcherType>,
+        leaf_count: usize,
+        column_config: BatcherConfig,
+        tree_config: BatcherConfig,
+        rows_to_discard: usize,
+        queue_depth: usize,
+    ) -> Result<Self, Error> {
+        Self::create(
+            t,
+            None,
+            None,
+            leaf_count,
+            column_config,
+            tree_config,
+            rows_to_discard,
+            queue_depth,
+        )
+    }
+
+    /// Create a pipeline as by `new`, hashing columns and tree with `BatcherType::Custom` batchers on the backends
+    /// created by `column_backend` and `tree_backend` respectively.
+    pub fn with_backends(
+        column_backend: BackendFactory<ColumnArity>,
+        tree_backend: BackendFactory<TreeArity>,
+        leaf_count: usize,
+        column_config: BatcherConfig,
+        tree_config: BatcherConfig,
+        rows_to_discard: usize,
+        queue_depth: usize,
+    ) -> Result<Self, Error> {
+        Self::create(
+            Some(BatcherType::Custom),
+            Some(column_backend),
+            Some(tree_backend),
+            leaf_count,
+            column_config,
+            tree_config,
+            rows_to_discard,
+            queue_depth,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn create(
+        t: Option<BatcherType>,
+        column_backend: Option<BackendFactory<ColumnArity>>,
+        tree_backend: Option<BackendFactory<TreeArity>>,
+        leaf_count: usize,
+        column_config: BatcherConfig,
+        tree_config: BatcherConfig,
+        rows_to_discard: usize,
+        queue_depth: usize,
+    ) -> Result<Self, Error> {
+        column_config.validate()?;
+        let tree_builder = TreeBuilder::<TreeArity>::create(
+            t,
+            tree_backend,
+            leaf_count,
+            tree_config,
+            rows_to_discard,
+        )?;
+
+        let (sender, receiver) = sync_channel(queue_depth);
+        let (ready_sender, ready_receiver) = sync_channel(1);
+        let worker = thread::spawn(move || {
+            hash_columns::<ColumnArity>(
+                t,
+                column_backend,
+                column_config,
+                leaf_count,
+                ready_sender,
+                receiver,
+            )
+        });
+
+        // Surface batcher initialization errors now, rather than on the first batch.
+        ready_receiver
+            .recv()
+            .map_err(|_| Error::Other("column worker exited before initializing".to_string()))??;
+
+        Ok(Self {
+            leaf_count,
+            enqueued: 0,
+            sender: Some(sender),
+            worker: Some(worker),
+            tree_builder,
+            rows_to_discard,
+            cancellation: None,
+        })
+    }
+
+    /// Report progress of building the tree, once all columns are hashed, to `observer`.
+    pub fn set_progress_observer(&mut self, observer: Arc<dyn ProgressObserver>) {
+        self.tree_builder.set_progress_observer(observer);
+    }
+
+    /// Check `token` before enqueuing each batch of columns, and between batches of the tree build.
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.tree_builder.set_cancellation_token(token.clone());
+        self.cancellation = Some(token);
+    }
+
+    /// Checkpoint the rows of the tree as they are built. See `TreeBuilder::set_checkpoint`.
+    pub fn set_checkpoint(&mut self, checkpoint: Checkpoint) {
+        self.tree_builder.set_checkpoint(checkpoint);
+    }
+
+    /// Enqueue `columns` for hashing, blocking while the queue is full.
+    pub fn add_columns(
+        &mut self,
+        columns: Vec<GenericArray<Fr, ColumnArity>>,
+    ) -> Result<(), Error> {
+        let end = self.enqueued + columns.len();
+
+        if end > self.leaf_count {
+            return Err(Error::Other("too many columns".to_string()));
+        }
+
+        if let Some(token) = &self.cancellation {
+            if token.is_cancelled() {
+                return Err(Error::Cancelled);
+            }
+        }
+
+        let sender = self
+            .sender
+            .as_ref()
+            .ok_or_else(|| Error::Other("pipeline already finished".to_string()))?;
+
+        if sender.send(columns).is_err() {
+            // The worker only hangs up early when it fails, so report its error.
+            return Err(self
+                .join_worker()
+                .err()
+                .unwrap_or_else(|| Error::Other("column worker exited early".to_string())));
+        }
+        self.enqueued = end;
+
+        Ok(())
+    }
+
+    /// Wait for all enqueued columns to be hashed, then build the tree from the column hashes.
+    pub fn finish(mut self) -> Result<(Vec<Fr>, Vec<Fr>), Error> {
+        let column_hashes = self.join_worker()?;
+
+        if column_hashes.len() != self.leaf_count {
+            return Err(Error::Other(format!(
+                "expected {} columns, but {} were added",
+                self.leaf_count,
+                column_hashes.len()
+            )));
+        }
+
+        info!("ColumnTreePipeline.finish: columns hashed, building tree");
+        self.tree_builder.add_final_leaves(&column_hashes)
+    }
+
+    /// `tree_size` returns the number of nodes in the tree to cache.
+    /// This excludes the base row (of column hashes) and the following `rows_to_discard` rows.
+    pub fn tree_size(&self) -> usize {
+        self.tree_builder.tree_size(self.rows_to_discard)
+    }
+
+    fn join_worker(&mut self) -> Result<Vec<Fr>, Error> {
+        // Hanging up lets the worker drain the queue and exit.
+        self.sender.take();
+
+        match self.worker.take() {
+            Some(worker) => worker
+                .join()
+                .map_err(|_| Error::Other("column worker panicked".to_string()))?,
+            None => Err(Error::Other("pipeline already finished".to_string())),
+        }
+    }
+}
+
+impl<ColumnArity, TreeArity> Drop for ColumnTreePipeline<ColumnArity, TreeArity>
+where
+    ColumnArity: Arity<Fr>,
+    TreeArity: Arity<Fr>,
+{
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Body of the worker thread: hash batches of columns, in order, until the queue is closed.
+/// The batcher is created (and dropped) on the worker thread, which is the only place it is used.
+fn hash_columns<ColumnArity>(
+    t: Option<BatcherType>,
+    backend: Option<BackendFactory<ColumnArity>>,
+    config: BatcherConfig,
+    leaf_count: usize,
+    ready: SyncSender<Result<(), Error>>,
+    batches: Receiver<Vec<GenericArray<Fr, ColumnArity>>>,
+) -> Result<Vec<Fr>, Error>
+where
+    ColumnArity: Arity<Fr>,
+{
+    let constants = PoseidonConstants::<Bls12, ColumnArity>::new();
+
+    let mut batcher = match t.map(|t| {
+        Batcher::<ColumnArity>::with_factory(DEFAULT_STRENGTH, &t, &config, backend.as_ref())
+    }) {
+        Some(Err(e)) => {
+            let _ = ready.send(Err(e.clone()));
+            return Err(e);
+        }
+        Some(Ok(batcher)) => Some(batcher),
+        None => None,
+    };
+    let _ = ready.send(Ok(()));
+
+    let mut column_hashes = Vec::with_capacity(leaf_count);
+    for columns in batches.iter() {
+        match batcher {
+            Some(ref mut batcher) => match batcher.hash(&columns) {
+                Ok(hashes) => column_hashes.extend(hashes),
+                Err(e) => {
+                    if let Err(release_error) = batcher.release() {
+                        warn!("Failed to release column batcher: {}", release_error);
+                    }
+                    return Err(e);
+                }
+            },
+            None => column_hashes.extend(
+                columns
+                    .iter()
+                    .map(|column| Poseidon::new_with_preimage(&column, &constants).hash()),
+            ),
+        }
+    }
+
+    // Release the device before the tree is built, which may need it.
+    if let Some(batcher) = &mut batcher {
+        batcher.release()?;
+    }
+
+    Ok(column_hashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::column_tree_builder::{ColumnTreeBuilder, ColumnTreeBuilderTrait};
+    use crate::fake_device::{fake_backend_factory, fake_backends, FakeOperation};
+    use ff::Field;
+    use generic_array::sequence::GenericSequence;
+    use generic_array::typenum::{U11, U8};
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    #[test]
+    fn test_column_tree_pipeline() {
+        // 16KiB tree has 512 leaves.
+        test_column_tree_pipeline_aux(None, 512, 32);
+        test_column_tree_pipeline_aux(Some(BatcherType::CPU), 512, 32);
+        test_column_tree_pipeline_aux(Some(BatcherType::Custom), 512, 32);
+
+        #[cfg(all(feature = "gpu", not(target_os = "macos")))]
+        test_column_tree_pipeline_aux(Some(BatcherType::GPU), 512, 32);
+    }
+
+    fn test_column_tree_pipeline_aux(
+        batcher_type: Option<BatcherType>,
+        leaves: usize,
+        num_batches: usize,
+    ) {
+        let mut rng = XorShiftRng::from_seed(crate::TEST_SEED);
+        let batch_size = leaves / num_batches;
+
+        let columns: Vec<GenericArray<Fr, U11>> = (0..leaves)
+            .map(|_| GenericArray::<Fr, U11>::generate(|_| Fr::random(&mut rng)))
+            .collect();
+
+        let mut builder = ColumnTreeBuilder::<U11, U8>::new(
+            None,
+            leaves,
+            BatcherConfig::new(leaves),
+            BatcherConfig::new(512),
+            0,
+        )
+        .unwrap();
+        let (expected_base, expected_tree) = builder.add_final_columns(&columns).unwrap();
+
+        // A queue depth of one makes the caller wait on the worker for most batches.
+        let (column_config, tree_config) =
+            (BatcherConfig::new(batch_size), BatcherConfig::new(512));
+        let mut pipeline = match batcher_type {
+            Some(BatcherType::Custom) => {
+                let (control, column_factory) = fake_backends();
+                ColumnTreePipeline::<U11, U8>::with_backends(
+                    column_factory,
+                    fake_backend_factory(&control),
+                    leaves,
+                    column_config,
+                    tree_config,
+                    0,
+                    1,
+                )
+            }
+            _ => ColumnTreePipeline::<U11, U8>::new(
+                batcher_type,
+                leaves,
+                column_config,
+                tree_config,
+                0,
+                1,
+            ),
+        }
+        .unwrap();
+        for batch in columns.chunks(batch_size) {
+            pipeline.add_columns(batch.to_vec()).unwrap();
+        }
+        let expected_size = pipeline.tree_size();
+        let (base, tree) = pipeline.finish().unwrap();
+
+        assert_eq!(expected_base, base);
+        assert_eq!(expected_tree, tree);
+        assert_eq!(expected_size, tree.len());
+    }
+
+    #[test]
+    fn test_column_tree_pipeline_column_count() {
+        let leaves = 64;
+        let column = GenericArray::<Fr, U11>::generate(|_| Fr::one());
+
+        let mut pipeline = ColumnTreePipeline::<U11, U8>::new(
+            None,
+            leaves,
+            BatcherConfig::new(leaves),
+            BatcherConfig::new(leaves),
+            0,
+            2,
+        )
+        .unwrap();
+        assert!(pipeline.add_columns(vec![column; leaves + 1]).is_err());
+
+        pipeline.add_columns(vec![column; leaves - 1]).unwrap();
+        assert!(pipeline.finish().is_err(), "finished with missing columns");
+    }
+
+    #[test]
+    fn test_column_tree_pipeline_release() {
+        let leaves = 64;
+        let (control, column_factory) = fake_backends();
+        let new_pipeline = || {
+            ColumnTreePipeline::<U11, U8>::with_backends(
+                column_factory.clone(),
+                fake_backend_factory(&control),
+                leaves,
+                BatcherConfig::new(leaves),
+                BatcherConfig::new(leaves),
+                0,
+                2,
+            )
+            .unwrap()
+        };
+        let columns = vec![GenericArray::<Fr, U11>::generate(|_| Fr::one()); leaves];
+
+        // The worker releases the column batcher once the columns are hashed, and the build its tree batcher.
+        let mut pipeline = new_pipeline();
+        pipeline.add_columns(columns.clone()).unwrap();
+        pipeline.finish().unwrap();
+        control.assert_released(2);
+
+        // A failed batch releases the column batcher on the worker, and no tree batcher is created.
+        let mut pipeline = new_pipeline();
+        control.fail_next(FakeOperation::Submit, 1, Error::DecodingError);
+        pipeline.add_columns(columns).unwrap();
+        assert!(pipeline.finish().is_err());
+        control.assert_released(3);
+    }
+}
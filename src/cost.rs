@@ -0,0 +1,313 @@
+use crate::circuit::{poseidon_hash_with_strategy, Elt, SynthesisStrategy};
+use crate::error::Error;
+use crate::poseidon::{Arity, PoseidonConstants};
+use crate::Strength;
+use bellperson::gadgets::num::AllocatedNum;
+use bellperson::{ConstraintSystem, Index, LinearCombination, SynthesisError, Variable};
+use ff::{Field, ScalarEngine};
+use std::marker::PhantomData;
+
+/// The cost of a circuit, in the terms which determine proving cost for R1CS backends.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CircuitCost {
+    pub constraints: usize,
+    pub aux_variables: usize,
+    /// Total number of terms in the A, B and C linear combinations of all constraints.
+    pub lc_terms: usize,
+    /// Number of terms in the largest linear combination.
+    pub max_lc_terms: usize,
+}
+
+/// Options affecting the cost of a hash circuit.
+#[derive(Clone, Debug, Default)]
+pub struct CostOptions {
+    /// Number of preimage elements which are constants rather than allocated (see `circuit::poseidon_hash_elts`).
+    pub constant_inputs: usize,
+    pub strategy: SynthesisStrategy,
+}
+
+/// Return the cost of `circuit::poseidon_hash` (or `circuit::poseidon_hash_with_strategy`, with constant inputs or
+/// another strategy) for arity `A` and `strength`. The cost of allocating the preimage is not included. Returns
+/// `Error::InvalidConfiguration` if `options` has more constant inputs than the arity.
+pub fn poseidon_hash_cost<E, A>(
+    strength: Strength,
+    options: &CostOptions,
+) -> Result<CircuitCost, Error>
+where
+    E: ScalarEngine,
+    A: Arity<E::Fr>,
+{
+    let arity = A::to_usize();
+    if options.constant_inputs > arity {
+        return Err(Error::InvalidConfiguration(format!(
+            "{} constant inputs is more than arity {}",
+            options.constant_inputs, arity
+        )));
+    }
+
+    let constants = PoseidonConstants::<E, A>::new_with_strength(strength);
+    let mut cs = CountingConstraintSystem::<E>::new();
+
+    let synthesis_error = |e: SynthesisError| Error::Other(e.to_string());
+    let preimage = (0..arity)
+        .map(|i| {
+            if i < options.constant_inputs {
+                Ok(Elt::num_from_fr::<CountingConstraintSystem<E>>(E::Fr::one()))
+            } else {
+                AllocatedNum::alloc(cs.namespace(|| format!("preimage {}", i)), || {
+                    Err(SynthesisError::AssignmentMissing)
+                })
+                .map(Elt::Allocated)
+            }
+        })
+        .collect::<Result<_, _>>()
+        .map_err(synthesis_error)?;
+    let before = cs.cost();
+
+    poseidon_hash_with_strategy(&mut cs, preimage, &constants, options.strategy)
+        .map_err(synthesis_error)?;
+
+    Ok(cs.cost_since(&before))
+}
+
+/// `CountingConstraintSystem` records the cost of the constraints synthesized into it, without evaluating any
+/// witness values. It can be used to measure the cost of any circuit.
+pub struct CountingConstraintSystem<E: ScalarEngine> {
+    cost: CircuitCost,
+    /// Number of terms in the largest linear combination of each constraint, in order.
+    max_lc_terms: Vec<usize>,
+    inputs: usize,
+    _e: PhantomData<E>,
+}
+
+impl<E: ScalarEngine> CountingConstraintSystem<E> {
+    pub fn new() -> Self {
+        Self {
+            cost: CircuitCost::default(),
+            max_lc_terms: Vec::new(),
+            // The first input is `one`.
+            inputs: 1,
+            _e: PhantomData,
+        }
+    }
+
+    /// The cost of everything synthesized so far.
+    pub fn cost(&self) -> CircuitCost {
+        self.cost
+    }
+
+    /// The cost of everything synthesized since `before` was returned by `cost`, including the largest linear
+    /// combination of only the constraints since then.
+    pub fn cost_since(&self, before: &CircuitCost) -> CircuitCost {
+        CircuitCost {
+            constraints: self.cost.constraints - before.constraints,
+            aux_variables: self.cost.aux_variables - before.aux_variables,
+            lc_terms: self.cost.lc_terms - before.lc_terms,
+            max_lc_terms: self.max_lc_terms[before.constraints..]
+                .iter()
+                .cloned()
+                .max()
+                .unwrap_or(0),
+        }
+    }
+}
+
+impl<E: ScalarEngine> ConstraintSystem<E> for CountingConstraintSystem<E> {
+    type Root = Self;
+
+    fn alloc<F, A, AR>(&mut self, _annotation: A, _f: F) -> Result<Variable, SynthesisError>
+    where
+        F: FnOnce() -> Result<E::Fr, SynthesisError>,
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+    {
+        self.cost.aux_variables += 1;
+        Ok(Variable::new_unchecked(Index::Aux(
+            self.cost.aux_variables - 1,
+        )))
+    }
+
+    fn alloc_input<F, A, AR>(&mut self, _annotation: A, _f: F) -> Result<Variable, SynthesisError>
+    where
+        F: FnOnce() -> Result<E::Fr, SynthesisError>,
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+    {
+        self.inputs += 1;
+        Ok(Variable::new_unchecked(Index::Input(self.inputs - 1)))
+    }
+
+    fn enforce<A, AR, LA, LB, LC>(&mut self, _annotation: A, a: LA, b: LB, c: LC)
+    where
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+        LA: FnOnce(LinearCombination<E>) -> LinearCombination<E>,
+        LB: FnOnce(LinearCombination<E>) -> LinearCombination<E>,
+        LC: FnOnce(LinearCombination<E>) -> LinearCombination<E>,
+    {
+        self.cost.constraints += 1;
+        let mut max_terms = 0;
+        for lc in &[
+            a(LinearCombination::zero()),
+            b(LinearCombination::zero()),
+            c(LinearCombination::zero()),
+        ] {
+            let terms = lc.iter().count();
+            self.cost.lc_terms += terms;
+            max_terms = usize::max(max_terms, terms);
+        }
+        self.cost.max_lc_terms = usize::max(self.cost.max_lc_terms, max_terms);
+        self.max_lc_terms.push(max_terms);
+    }
+
+    fn push_namespace<NR, N>(&mut self, _name_fn: N)
+    where
+        NR: Into<String>,
+        N: FnOnce() -> NR,
+    {
+    }
+
+    fn pop_namespace(&mut self) {}
+
+    fn get_root(&mut self) -> &mut Self::Root {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bellperson::util_cs::test_cs::TestConstraintSystem;
+    use generic_array::typenum;
+    use paired::bls12_381::{Bls12, Fr};
+
+    #[test]
+    fn test_poseidon_hash_cost() {
+        // Constraint counts are those hard-coded in `circuit::tests::test_poseidon_hash`.
+        assert_eq!(
+            311,
+            poseidon_hash_cost::<Bls12, typenum::U2>(Strength::Standard, &Default::default())
+                .unwrap()
+                .constraints
+        );
+        assert_eq!(
+            565,
+            poseidon_hash_cost::<Bls12, typenum::U8>(Strength::Strengthened, &Default::default())
+                .unwrap()
+                .constraints
+        );
+
+        for strength in &[Strength::Standard, Strength::Strengthened] {
+            for strategy in &[
+                SynthesisStrategy::Standard,
+                SynthesisStrategy::AllocateEveryRound,
+                SynthesisStrategy::FusedSBox,
+            ] {
+                for constant_inputs in 0..=4 {
+                    test_poseidon_hash_cost_aux::<typenum::U4>(
+                        *strength,
+                        constant_inputs,
+                        *strategy,
+                    );
+                }
+                test_poseidon_hash_cost_aux::<typenum::U2>(*strength, 0, *strategy);
+                test_poseidon_hash_cost_aux::<typenum::U8>(*strength, 1, *strategy);
+                test_poseidon_hash_cost_aux::<typenum::U11>(*strength, 0, *strategy);
+            }
+        }
+    }
+
+    #[test]
+    fn test_synthesis_strategy_cost() {
+        let cost = |strategy| {
+            let options = CostOptions {
+                strategy,
+                ..Default::default()
+            };
+            poseidon_hash_cost::<Bls12, typenum::U8>(Strength::Standard, &options).unwrap()
+        };
+        let standard = cost(SynthesisStrategy::Standard);
+        let allocate_every_round = cost(SynthesisStrategy::AllocateEveryRound);
+        let fused = cost(SynthesisStrategy::FusedSBox);
+
+        // Allocating every round trades constraints for smaller linear combinations; fusing does the opposite.
+        assert!(allocate_every_round.constraints > standard.constraints);
+        assert!(allocate_every_round.max_lc_terms < standard.max_lc_terms);
+        assert!(fused.constraints < standard.constraints);
+        assert!(fused.lc_terms > standard.lc_terms);
+    }
+
+    #[test]
+    fn test_poseidon_hash_cost_invalid() {
+        let options = CostOptions {
+            constant_inputs: 5,
+            ..Default::default()
+        };
+        match poseidon_hash_cost::<Bls12, typenum::U4>(Strength::Standard, &options) {
+            Err(Error::InvalidConfiguration(_)) => (),
+            other => panic!("expected an invalid configuration, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_cost_since() {
+        let mut cs = CountingConstraintSystem::<Bls12>::new();
+        let a = cs.alloc(|| "a", || Ok(Fr::one())).unwrap();
+        let b = cs.alloc(|| "b", || Ok(Fr::one())).unwrap();
+
+        cs.enforce(|| "wide", |lc| lc + a + b, |lc| lc + a, |lc| lc + b);
+        let before = cs.cost();
+        cs.enforce(|| "narrow", |lc| lc + a, |lc| lc + a, |lc| lc + b);
+
+        // The largest linear combination is only taken over the constraints since `before`.
+        let since = cs.cost_since(&before);
+        assert_eq!(1, since.constraints);
+        assert_eq!(0, since.aux_variables);
+        assert_eq!(3, since.lc_terms);
+        assert_eq!(1, since.max_lc_terms);
+        assert_eq!(2, cs.cost().max_lc_terms);
+    }
+
+    fn test_poseidon_hash_cost_aux<A>(
+        strength: Strength,
+        constant_inputs: usize,
+        strategy: SynthesisStrategy,
+    ) where
+        A: Arity<Fr>,
+    {
+        let options = CostOptions {
+            constant_inputs,
+            strategy,
+        };
+        let cost = poseidon_hash_cost::<Bls12, A>(strength, &options).unwrap();
+
+        // Synthesize the same circuit, with values.
+        let mut cs = TestConstraintSystem::<Bls12>::new();
+        let constants = PoseidonConstants::<Bls12, A>::new_with_strength(strength);
+        let preimage = (0..A::to_usize())
+            .map(|i| {
+                let fr = crate::scalar_from_u64::<Fr>(i as u64);
+                if i < constant_inputs {
+                    Elt::num_from_fr::<TestConstraintSystem<Bls12>>(fr)
+                } else {
+                    Elt::Allocated(
+                        AllocatedNum::alloc(cs.namespace(|| format!("preimage {}", i)), || Ok(fr))
+                            .unwrap(),
+                    )
+                }
+            })
+            .collect();
+        let before = cs.num_constraints();
+        poseidon_hash_with_strategy(&mut cs, preimage, &constants, strategy).unwrap();
+
+        assert!(cs.is_satisfied());
+        assert_eq!(cs.num_constraints() - before, cost.constraints);
+        // Every constraint of the hash allocates exactly one variable.
+        assert_eq!(cost.constraints, cost.aux_variables);
+        assert!(cost.lc_terms >= 3 * cost.constraints);
+        if constant_inputs < A::to_usize() {
+            // Allocating MDS products enforces linear combinations of the whole state.
+            assert!(cost.max_lc_terms > A::to_usize());
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! Authenticated encryption with the Poseidon permutation, in duplex mode.
+//!
+//! The state is initialized as `[domain tag, k0, k1, nonce, 0, ...]` and permuted. Each chunk of up to `arity`
+//! message elements is then added to the rate (every element but the first) to produce the ciphertext, which
+//! replaces the rate before the next permutation. After the last chunk, the second element of the state is the
+//! authentication tag, which is appended to the ciphertext.
+//!
+//! The domain tag is `2^64 + message length`, which separates encryption from hashing (whose arity tags are all
+//! smaller) and binds the message length. A nonce must never be reused with the same key.
+use crate::error::Error;
+use crate::poseidon::{Arity, Poseidon, PoseidonConstants};
+use crate::scalar_from_u64;
+use ff::{Field, ScalarEngine};
+
+/// The first element of the initial encryption state, for a message of `len` elements.
+pub(crate) fn domain_tag<E: ScalarEngine>(len: usize) -> E::Fr {
+    let mut tag = scalar_from_u64::<E::Fr>(1 << 32);
+    tag.square();
+    tag.add_assign(&scalar_from_u64::<E::Fr>(len as u64));
+    tag
+}
+
+/// Encrypt `message` with `key` and `nonce`, returning the ciphertext followed by the authentication tag.
+/// The arity must be at least 3, so the initial state has room for the key and nonce.
+pub fn encrypt<E, A>(
+    constants: &PoseidonConstants<E, A>,
+    key: &[E::Fr; 2],
+    nonce: E::Fr,
+    message: &[E::Fr],
+) -> Result<Vec<E::Fr>, Error>
+where
+    E: ScalarEngine,
+    A: Arity<E::Fr>,
+{
+    let mut p = initial_state(constants, key, nonce, message.len())?;
+    let mut ciphertext = Vec::with_capacity(message.len() + 1);
+
+    for chunk in message.chunks(constants.arity()) {
+        for (i, m) in chunk.iter().enumerate() {
+            p.elements[i + 1].add_assign(m);
+            ciphertext.push(p.elements[i + 1]);
+        }
+        p.permute();
+    }
+    ciphertext.push(p.elements[1]);
+
+    Ok(ciphertext)
+}
+
+/// Decrypt `ciphertext` (including its trailing authentication tag) with `key` and `nonce`. Returns
+/// `Error::TagMismatch` if the ciphertext was not produced by `encrypt` with the same key and nonce.
+pub fn decrypt<E, A>(
+    constants: &PoseidonConstants<E, A>,
+    key: &[E::Fr; 2],
+    nonce: E::Fr,
+    ciphertext: &[E::Fr],
+) -> Result<Vec<E::Fr>, Error>
+where
+    E: ScalarEngine,
+    A: Arity<E::Fr>,
+{
+    let (tag, ciphertext) = ciphertext
+        .split_last()
+        .ok_or_else(|| Error::Other("ciphertext is missing its tag".to_string()))?;
+    let mut p = initial_state(constants, key, nonce, ciphertext.len())?;
+    let mut message = Vec::with_capacity(ciphertext.len());
+
+    for chunk in ciphertext.chunks(constants.arity()) {
+        for (i, c) in chunk.iter().enumerate() {
+            let mut m = *c;
+            m.sub_assign(&p.elements[i + 1]);
+            message.push(m);
+            p.elements[i + 1] = *c;
+        }
+        p.permute();
+    }
+
+    if p.elements[1] != *tag {
+        return Err(Error::TagMismatch);
+    }
+
+    Ok(message)
+}
+
+/// Create the initial state for a message of `len` elements, and permute it.
+fn initial_state<'a, E, A>(
+    constants: &'a PoseidonConstants<E, A>,
+    key: &[E::Fr; 2],
+    nonce: E::Fr,
+    len: usize,
+) -> Result<Poseidon<'a, E, A>, Error>
+where
+    E: ScalarEngine,
+    A: Arity<E::Fr>,
+{
+    if constants.arity() < 3 {
+        return Err(Error::Other(format!(
+            "encryption requires arity of at least 3, not {}",
+            constants.arity()
+        )));
+    }
+
+    let mut p = Poseidon::new(constants);
+    p.elements[0] = domain_tag::<E>(len);
+    p.elements[1] = key[0];
+    p.elements[2] = key[1];
+    p.elements[3] = nonce;
+    p.permute();
+
+    Ok(p)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use generic_array::typenum::{U2, U4, U8};
+    use paired::bls12_381::{Bls12, Fr};
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    #[test]
+    fn test_encryption_round_trip() {
+        for len in &[0, 1, 3, 4, 5, 9] {
+            test_encryption_round_trip_aux::<U4>(*len);
+            test_encryption_round_trip_aux::<U8>(*len);
+        }
+    }
+
+    fn test_encryption_round_trip_aux<A: Arity<Fr>>(len: usize) {
+        let mut rng = XorShiftRng::from_seed(crate::TEST_SEED);
+        let constants = PoseidonConstants::<Bls12, A>::new();
+
+        let key = [Fr::random(&mut rng), Fr::random(&mut rng)];
+        let nonce = Fr::random(&mut rng);
+        let message: Vec<Fr> = (0..len).map(|_| Fr::random(&mut rng)).collect();
+
+        let ciphertext = encrypt(&constants, &key, nonce, &message).unwrap();
+        assert_eq!(len + 1, ciphertext.len());
+        if len > 0 {
+            assert_ne!(message[..], ciphertext[..len]);
+        }
+
+        let decrypted = decrypt(&constants, &key, nonce, &ciphertext).unwrap();
+        assert_eq!(message, decrypted);
+
+        // Tampering with the ciphertext, or the tag, is detected.
+        for i in 0..ciphertext.len() {
+            let mut tampered = ciphertext.clone();
+            tampered[i].add_assign(&Fr::one());
+            assert_mismatch(decrypt(&constants, &key, nonce, &tampered));
+        }
+
+        // So is decrypting with the wrong key or nonce.
+        let wrong_key = [key[0], Fr::random(&mut rng)];
+        assert_mismatch(decrypt(&constants, &wrong_key, nonce, &ciphertext));
+        assert_mismatch(decrypt(&constants, &key, Fr::random(&mut rng), &ciphertext));
+
+        // Or a truncated ciphertext.
+        if len > 0 {
+            let mut truncated = ciphertext[..len - 1].to_vec();
+            truncated.push(ciphertext[len]);
+            assert_mismatch(decrypt(&constants, &key, nonce, &truncated));
+        }
+    }
+
+    fn assert_mismatch(result: Result<Vec<Fr>, Error>) {
+        match result {
+            Err(Error::TagMismatch) => (),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("decryption should have failed"),
+        }
+    }
+
+    #[test]
+    fn test_encryption_arity() {
+        let constants = PoseidonConstants::<Bls12, U2>::new();
+        let key = [Fr::one(), Fr::one()];
+
+        assert!(encrypt(&constants, &key, Fr::one(), &[Fr::one()]).is_err());
+    }
+}
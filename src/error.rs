@@ -10,6 +10,25 @@ pub enum Error {
     /// The provided leaf was not found in the tree
     GPUError(String),
     DecodingError,
+    /// The operation was cancelled through its `CancellationToken`.
+    Cancelled,
+    /// A checkpoint failed verification, or belongs to a different tree.
+    InvalidCheckpoint(String),
+    /// Decryption failed, because the authentication tag did not match.
+    TagMismatch,
+    /// A configuration value is missing, malformed or out of range.
+    InvalidConfiguration(String),
+    /// Preimages of arity `actual` were given to a hasher of arity `expected`.
+    ArityMismatch {
+        expected: usize,
+        actual: usize,
+    },
+    /// A backend cannot hash preimages of this arity.
+    UnsupportedArity(usize),
+    /// The hash of the preimage at `index` in a batch did not match the hash computed on the CPU.
+    HashMismatch {
+        index: usize,
+    },
     Other(String),
 }
 
@@ -25,6 +44,21 @@ impl fmt::Display for Error {
             Error::IndexOutOfBounds => write!(f, "The referenced index is outs of bounds."),
             Error::GPUError(s) => write!(f, "GPU Error: {}", s),
             Error::DecodingError => write!(f, "PrimeFieldDecodingError"),
+            Error::Cancelled => write!(f, "The operation was cancelled."),
+            Error::InvalidCheckpoint(s) => write!(f, "Invalid checkpoint: {}", s),
+            Error::TagMismatch => write!(f, "The authentication tag did not match."),
+            Error::InvalidConfiguration(s) => write!(f, "Invalid configuration: {}", s),
+            Error::ArityMismatch { expected, actual } => write!(
+                f,
+                "Expected preimages of arity {}, but got arity {}.",
+                expected, actual
+            ),
+            Error::UnsupportedArity(arity) => write!(f, "Arity {} is not supported.", arity),
+            Error::HashMismatch { index } => write!(
+                f,
+                "The hash of preimage {} in the batch did not match the CPU hash.",
+                index
+            ),
             Error::Other(s) => write!(f, "{}", s),
         }
     }
@@ -0,0 +1,446 @@
+use crate::backend::{BackendFactory, DeviceBatchHasher, HashBackend};
+use crate::batch_hasher::BatcherConfig;
+use crate::error::Error;
+use crate::poseidon::{Poseidon, PoseidonConstants};
+use crate::{Arity, Strength};
+use generic_array::GenericArray;
+use paired::bls12_381::{Bls12, Fr};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// The operations of a `HashBackend`, for injecting failures into and counting calls to a `FakeDevice`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FakeOperation {
+    Init,
+    UploadConstants,
+    Submit,
+    Sync,
+    Release,
+    BuildTree,
+}
+
+/// `FakeDeviceControl` injects failures and latency into the `FakeDevice`s sharing it, and records their calls.
+/// Clones share the same state, so a test can keep a clone to control a device after handing it to a batcher.
+#[derive(Clone, Debug, Default)]
+pub struct FakeDeviceControl {
+    state: Arc<Mutex<ControlState>>,
+}
+
+#[derive(Debug, Default)]
+struct ControlState {
+    failures: HashMap<FakeOperation, VecDeque<Error>>,
+    calls: HashMap<FakeOperation, usize>,
+    latency: Duration,
+    preimages_hashed: usize,
+    largest_batch: usize,
+    whole_tree_leaves: Option<usize>,
+}
+
+impl FakeDeviceControl {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Fail the next `count` calls of `operation` with `error`, after any failures already injected.
+    pub fn fail_next(&self, operation: FakeOperation, count: usize, error: Error) {
+        let mut state = self.state.lock().unwrap();
+        let failures = state
+            .failures
+            .entry(operation)
+            .or_insert_with(VecDeque::new);
+        for _ in 0..count {
+            failures.push_back(error.clone());
+        }
+    }
+
+    /// Delay every submitted batch by `latency`.
+    pub fn set_latency(&self, latency: Duration) {
+        self.state.lock().unwrap().latency = latency;
+    }
+
+    /// Number of calls of `operation` so far, including failed calls.
+    pub fn calls(&self, operation: FakeOperation) -> usize {
+        *self
+            .state
+            .lock()
+            .unwrap()
+            .calls
+            .get(&operation)
+            .unwrap_or(&0)
+    }
+
+    /// Simulate a whole-tree entry point for trees of `leaves` leaves, or none.
+    pub fn set_whole_tree_leaves(&self, leaves: Option<usize>) {
+        self.state.lock().unwrap().whole_tree_leaves = leaves;
+    }
+
+    /// Number of preimages successfully hashed so far.
+    pub fn preimages_hashed(&self) -> usize {
+        self.state.lock().unwrap().preimages_hashed
+    }
+
+    /// Number of preimages in the largest batch submitted so far.
+    pub fn largest_batch(&self) -> usize {
+        self.state.lock().unwrap().largest_batch
+    }
+
+    /// Assert that `devices` devices have been initialized, and that each has been synced and released.
+    #[cfg(test)]
+    pub(crate) fn assert_released(&self, devices: usize) {
+        for operation in &[
+            FakeOperation::Init,
+            FakeOperation::Sync,
+            FakeOperation::Release,
+        ] {
+            assert_eq!(devices, self.calls(*operation), "calls of {:?}", operation);
+        }
+    }
+
+    /// Record a call of `operation`, returning the injected failure if there is one.
+    fn call(&self, operation: FakeOperation) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        *state.calls.entry(operation).or_insert(0) += 1;
+
+        match state
+            .failures
+            .get_mut(&operation)
+            .and_then(|failures| failures.pop_front())
+        {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+/// `FakeDevice` is a `HashBackend` which simulates a device on the CPU, so code driving devices (scheduling, retries
+/// and tree building) can be tested without a GPU. It hashes exactly as the GPU does, and fails or stalls only as
+/// injected through its `FakeDeviceControl`, so its behavior is deterministic.
+#[derive(Debug)]
+pub struct FakeDevice<A>
+where
+    A: Arity<Fr>,
+{
+    control: FakeDeviceControl,
+    initialized: bool,
+    constants: Option<PoseidonConstants<Bls12, A>>,
+}
+
+impl<A> FakeDevice<A>
+where
+    A: Arity<Fr>,
+{
+    pub fn new(control: FakeDeviceControl) -> Self {
+        Self {
+            control,
+            initialized: false,
+            constants: None,
+        }
+    }
+
+    pub fn control(&self) -> &FakeDeviceControl {
+        &self.control
+    }
+}
+
+impl<A> Default for FakeDevice<A>
+where
+    A: Arity<Fr>,
+{
+    fn default() -> Self {
+        Self::new(FakeDeviceControl::new())
+    }
+}
+
+impl<A> HashBackend<A> for FakeDevice<A>
+where
+    A: Arity<Fr>,
+{
+    fn init(&mut self) -> Result<(), Error> {
+        self.control.call(FakeOperation::Init)?;
+        self.initialized = true;
+        Ok(())
+    }
+
+    fn upload_constants(&mut self, strength: Strength) -> Result<(), Error> {
+        self.control.call(FakeOperation::UploadConstants)?;
+        if !self.initialized {
+            return Err(Error::GPUError(
+                "fake device is not initialized".to_string(),
+            ));
+        }
+
+        self.constants = Some(PoseidonConstants::<Bls12, A>::new_with_strength(strength));
+        Ok(())
+    }
+
+    fn submit(&mut self, preimages: &[GenericArray<Fr, A>]) -> Result<Vec<Fr>, Error> {
+        self.control.call(FakeOperation::Submit)?;
+        {
+            let mut state = self.control.state.lock().unwrap();
+            state.largest_batch = usize::max(state.largest_batch, preimages.len());
+        }
+        let constants = self
+            .constants
+            .as_ref()
+            .ok_or_else(|| Error::GPUError("fake device has no constants".to_string()))?;
+
+        let latency = self.control.state.lock().unwrap().latency;
+        if latency > Duration::from_secs(0) {
+            thread::sleep(latency);
+        }
+
+        let hashes: Vec<Fr> = preimages
+            .iter()
+            .map(|preimage| Poseidon::new_with_preimage(&preimage, constants).hash())
+            .collect();
+        self.control.state.lock().unwrap().preimages_hashed += hashes.len();
+
+        Ok(hashes)
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        self.control.call(FakeOperation::Sync)
+    }
+
+    fn whole_tree_leaves(&self) -> Option<usize> {
+        self.constants
+            .as_ref()
+            .and_then(|_| self.control.state.lock().unwrap().whole_tree_leaves)
+    }
+
+    fn build_tree(&mut self, leaves: &[Fr]) -> Result<Vec<Fr>, Error> {
+        self.control.call(FakeOperation::BuildTree)?;
+        let constants = self
+            .constants
+            .as_ref()
+            .ok_or_else(|| Error::GPUError("fake device has no constants".to_string()))?;
+
+        let mut rows = Vec::new();
+        let mut row = leaves.to_vec();
+        while row.len() > 1 {
+            row = row
+                .chunks(A::to_usize())
+                .map(|preimage| Poseidon::new_with_preimage(preimage, constants).hash())
+                .collect();
+            rows.extend(&row);
+        }
+        self.control.state.lock().unwrap().preimages_hashed += rows.len();
+
+        Ok(rows)
+    }
+
+    fn release(&mut self) -> Result<(), Error> {
+        self.control.call(FakeOperation::Release)?;
+        self.initialized = false;
+        self.constants = None;
+        Ok(())
+    }
+}
+
+/// Create a `BackendFactory` of `FakeDevice`s sharing `control`, so tree builders can be run on fake devices.
+pub fn fake_backend_factory<A>(control: &FakeDeviceControl) -> BackendFactory<A>
+where
+    A: 'static + Arity<Fr>,
+{
+    let control = control.clone();
+    Arc::new(move || {
+        let device: Box<dyn HashBackend<A> + Send> = Box::new(FakeDevice::new(control.clone()));
+        Ok(device)
+    })
+}
+
+/// Create a `FakeDeviceControl`, and a `BackendFactory` of `FakeDevice`s sharing it.
+#[cfg(test)]
+pub(crate) fn fake_backends<A>() -> (FakeDeviceControl, BackendFactory<A>)
+where
+    A: 'static + Arity<Fr>,
+{
+    let control = FakeDeviceControl::new();
+    let factory = fake_backend_factory(&control);
+    (control, factory)
+}
+
+/// Create a hasher on a `FakeDevice` sharing `control`, configured by `config`.
+#[cfg(test)]
+pub(crate) fn fake_hasher<A>(
+    control: &FakeDeviceControl,
+    config: &BatcherConfig,
+) -> Result<DeviceBatchHasher<FakeDevice<A>, A>, Error>
+where
+    A: Arity<Fr>,
+{
+    DeviceBatchHasher::new(FakeDevice::new(control.clone()), Strength::Standard, config)
+}
+
+/// Generate `count` random preimages, and their hashes with standard strength.
+#[cfg(test)]
+pub(crate) fn random_preimages_and_hashes<A>(count: usize) -> (Vec<GenericArray<Fr, A>>, Vec<Fr>)
+where
+    A: Arity<Fr>,
+{
+    use ff::Field;
+    use generic_array::sequence::GenericSequence;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    let mut rng = XorShiftRng::from_seed(crate::TEST_SEED);
+    let preimages: Vec<GenericArray<Fr, A>> = (0..count)
+        .map(|_| GenericArray::generate(|_| Fr::random(&mut rng)))
+        .collect();
+    let constants = PoseidonConstants::<Bls12, A>::new();
+    let hashes = preimages
+        .iter()
+        .map(|p| Poseidon::new_with_preimage(&p, &constants).hash())
+        .collect();
+
+    (preimages, hashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BatchHasher;
+    use ff::Field;
+    use generic_array::sequence::GenericSequence;
+    use generic_array::typenum::U8;
+
+    fn config() -> BatcherConfig {
+        BatcherConfig {
+            retry_count: 3,
+            retry_backoff: Duration::from_millis(1),
+            ..BatcherConfig::new(64)
+        }
+    }
+
+    #[test]
+    fn test_fake_device() {
+        let control = FakeDeviceControl::new();
+        let mut hasher = fake_hasher::<U8>(&control, &config()).unwrap();
+        let (preimages, expected) = random_preimages_and_hashes::<U8>(10);
+
+        assert_eq!(expected, hasher.hash(&preimages).unwrap());
+        hasher.sync().unwrap();
+        assert_eq!(1, control.calls(FakeOperation::Init));
+        assert_eq!(1, control.calls(FakeOperation::UploadConstants));
+        assert_eq!(1, control.calls(FakeOperation::Submit));
+        assert_eq!(1, control.calls(FakeOperation::Sync));
+        assert_eq!(10, control.preimages_hashed());
+
+        // Transient failures are retried.
+        let failure = Error::GPUError("injected".to_string());
+        control.fail_next(FakeOperation::Submit, 2, failure.clone());
+        assert_eq!(expected, hasher.hash(&preimages).unwrap());
+        assert_eq!(4, control.calls(FakeOperation::Submit));
+
+        // Persistent failures are not.
+        control.fail_next(FakeOperation::Submit, 3, failure.clone());
+        assert!(hasher.hash(&preimages).is_err());
+        assert_eq!(7, control.calls(FakeOperation::Submit));
+        assert_eq!(20, control.preimages_hashed());
+
+        // Initialization failures are surfaced when creating the hasher.
+        let control = FakeDeviceControl::new();
+        control.fail_next(FakeOperation::Init, 1, failure);
+        assert!(fake_hasher::<U8>(&control, &config()).is_err());
+        assert_eq!(0, control.calls(FakeOperation::UploadConstants));
+    }
+
+    #[test]
+    fn test_fake_device_release() {
+        let control = FakeDeviceControl::new();
+        let mut hasher = fake_hasher::<U8>(&control, &config()).unwrap();
+        let preimages = vec![GenericArray::<Fr, U8>::generate(|_| Fr::one()); 4];
+        hasher.hash(&preimages).unwrap();
+
+        // Releasing waits for submitted work first, and happens only once.
+        hasher.release().unwrap();
+        hasher.release().unwrap();
+        assert_eq!(1, control.calls(FakeOperation::Sync));
+        assert_eq!(1, control.calls(FakeOperation::Release));
+        assert!(hasher.hash(&preimages).is_err());
+        assert!(hasher.sync().is_err());
+        drop(hasher);
+        assert_eq!(1, control.calls(FakeOperation::Release));
+
+        // Release errors are returned.
+        let control = FakeDeviceControl::new();
+        let mut hasher = fake_hasher::<U8>(&control, &config()).unwrap();
+        control.fail_next(
+            FakeOperation::Release,
+            1,
+            Error::GPUError("injected".to_string()),
+        );
+        assert!(hasher.release().is_err());
+        assert!(hasher.is_released());
+
+        // Dropping a hasher unreleased leaves the device alone.
+        let control = FakeDeviceControl::new();
+        drop(fake_hasher::<U8>(&control, &config()).unwrap());
+        assert_eq!(0, control.calls(FakeOperation::Sync));
+        assert_eq!(0, control.calls(FakeOperation::Release));
+
+        // A hasher which fails to upload its constants releases the device before returning the error.
+        let control = FakeDeviceControl::new();
+        control.fail_next(
+            FakeOperation::UploadConstants,
+            1,
+            Error::GPUError("injected".to_string()),
+        );
+        assert!(fake_hasher::<U8>(&control, &config()).is_err());
+        assert_eq!(1, control.calls(FakeOperation::Release));
+    }
+
+    #[test]
+    fn test_fake_device_memory_budget() {
+        let control = FakeDeviceControl::new();
+        let per_preimage = crate::backend::bytes_per_preimage::<U8>();
+        let config = BatcherConfig {
+            memory_budget: Some(10 * per_preimage + 1),
+            ..config()
+        };
+        let mut hasher = fake_hasher::<U8>(&control, &config).unwrap();
+        assert_eq!(10, hasher.max_batch_size());
+
+        // Larger batches are submitted in parts which fit the budget.
+        let preimages = vec![GenericArray::<Fr, U8>::generate(|_| Fr::one()); 25];
+        assert_eq!(25, hasher.hash(&preimages).unwrap().len());
+        assert_eq!(3, control.calls(FakeOperation::Submit));
+        assert_eq!(10, control.largest_batch());
+
+        // A budget too small for one preimage is invalid.
+        let config = BatcherConfig {
+            memory_budget: Some(per_preimage - 1),
+            ..config
+        };
+        match DeviceBatchHasher::new(FakeDevice::<U8>::default(), Strength::Standard, &config) {
+            Err(Error::InvalidConfiguration(_)) => (),
+            _ => panic!("expected an invalid configuration"),
+        }
+    }
+
+    #[test]
+    fn test_fake_device_whole_tree() {
+        use crate::tree_builder::{TreeBuilder, TreeBuilderTrait};
+
+        let control = FakeDeviceControl::new();
+        let mut hasher = fake_hasher::<U8>(&control, &config()).unwrap();
+        let leaves: Vec<Fr> = (0..64).map(|n| crate::scalar_from_u64(n)).collect();
+
+        // Without a whole-tree entry point, trees cannot be built in one call.
+        assert_eq!(None, hasher.whole_tree_leaves());
+        assert!(hasher.build_tree(&leaves).is_err());
+
+        control.set_whole_tree_leaves(Some(64));
+        assert_eq!(Some(64), hasher.whole_tree_leaves());
+        assert!(hasher.build_tree(&leaves[..8]).is_err());
+
+        let rows = hasher.build_tree(&leaves).unwrap();
+        let mut builder = TreeBuilder::<U8>::new(None, 64, config(), 0).unwrap();
+        let (_, expected) = builder.add_final_leaves(&leaves).unwrap();
+        assert_eq!(expected, rows);
+        assert_eq!(1, control.calls(FakeOperation::BuildTree));
+        assert_eq!(0, control.calls(FakeOperation::Submit));
+    }
+}
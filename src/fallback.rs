@@ -0,0 +1,256 @@
+use crate::batch_hasher::{Batcher, BatcherConfig, BatcherType};
+use crate::error::Error;
+use crate::poseidon::SimplePoseidonBatchHasher;
+use crate::{Arity, BatchHasher, Strength};
+use generic_array::GenericArray;
+use log::warn;
+use paired::bls12_381::Fr;
+
+/// Whether `error` shows that the device is failing, so hashing should continue on the CPU. Errors in the caller's
+/// use of the library (such as `InvalidConfiguration` or `Cancelled`) would recur on any backend, so are returned.
+pub fn should_fall_back(error: &Error) -> bool {
+    match error {
+        Error::GPUError(_)
+        | Error::DecodingError
+        | Error::ArityMismatch { .. }
+        | Error::UnsupportedArity(_)
+        | Error::HashMismatch { .. } => true,
+        _ => false,
+    }
+}
+
+/// `FallbackBatcher` hashes with a primary `Batcher`, such as a GPU, until it fails to be created or to hash a batch
+/// (after any retries of its own) with an error for which `should_fall_back` holds. From then on, every batch,
+/// including the failed one, is hashed on the CPU.
+///
+/// The backend which hashed each batch is recorded, so the results can be audited.
+pub struct FallbackBatcher<'a, A>
+where
+    A: Arity<Fr>,
+{
+    primary: Option<Batcher<'a, A>>,
+    primary_type: BatcherType,
+    fallback: Option<SimplePoseidonBatchHasher<'a, A>>,
+    strength: Strength,
+    max_batch_size: usize,
+    reason: Option<Error>,
+    sources: Vec<BatcherType>,
+}
+
+impl<'a, A> FallbackBatcher<'a, A>
+where
+    A: 'a + Arity<Fr>,
+{
+    /// Create a batcher of type `t`, falling back to the CPU if it cannot be created.
+    pub fn new(strength: Strength, t: &BatcherType, config: &BatcherConfig) -> Result<Self, Error> {
+        let config = BatcherConfig {
+            cpu_fallback: false,
+            ..config.clone()
+        };
+        Self::from_primary(
+            Batcher::new_with_strength(strength, t, &config),
+            *t,
+            strength,
+            &config,
+        )
+    }
+
+    /// Wrap `primary`, the result of creating a batcher of type `t`, falling back to the CPU if it is a device
+    /// failure.
+    pub fn from_primary(
+        primary: Result<Batcher<'a, A>, Error>,
+        t: BatcherType,
+        strength: Strength,
+        config: &BatcherConfig,
+    ) -> Result<Self, Error> {
+        let mut batcher = Self {
+            primary: None,
+            primary_type: t,
+            fallback: None,
+            strength,
+            max_batch_size: config.max_batch_size,
+            reason: None,
+            sources: Vec::new(),
+        };
+
+        match primary {
+            Ok(primary) => batcher.primary = Some(primary),
+            Err(e) if should_fall_back(&e) => batcher.fall_back(e)?,
+            Err(e) => return Err(e),
+        }
+
+        Ok(batcher)
+    }
+
+    /// The type of batcher currently hashing.
+    pub fn active(&self) -> BatcherType {
+        if self.primary.is_some() {
+            self.primary_type
+        } else {
+            BatcherType::CPU
+        }
+    }
+
+    /// The error which caused the switch to the CPU, if it happened.
+    pub fn fallback_reason(&self) -> Option<&Error> {
+        self.reason.as_ref()
+    }
+
+    /// The type of batcher which hashed each batch so far, in order.
+    pub fn batch_sources(&self) -> &[BatcherType] {
+        &self.sources
+    }
+
+    fn fall_back(&mut self, reason: Error) -> Result<(), Error> {
+        warn!(
+            "{:?} batcher failed: {}. Falling back to CPU",
+            self.primary_type, reason
+        );
+
+        // The failing device is released on a best-effort basis: its error is already being handled.
+        if let Some(mut primary) = self.primary.take() {
+            if let Err(e) = primary.release() {
+                warn!("Failed to release {:?} batcher: {}", self.primary_type, e);
+            }
+        }
+        self.fallback = Some(SimplePoseidonBatchHasher::new_with_strength(
+            self.strength,
+            self.max_batch_size,
+        )?);
+        self.reason = Some(reason);
+        Ok(())
+    }
+}
+
+impl<'a, A> BatchHasher<A> for FallbackBatcher<'a, A>
+where
+    A: 'a + Arity<Fr>,
+{
+    fn hash(&mut self, preimages: &[GenericArray<Fr, A>]) -> Result<Vec<Fr>, Error> {
+        if let Some(primary) = &mut self.primary {
+            match primary.hash(preimages) {
+                Ok(hashes) => {
+                    self.sources.push(self.primary_type);
+                    return Ok(hashes);
+                }
+                Err(e) if should_fall_back(&e) => self.fall_back(e)?,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let fallback = self
+            .fallback
+            .as_mut()
+            .expect("FallbackBatcher has neither a primary nor a fallback batcher");
+        let hashes = fallback.hash(preimages)?;
+        self.sources.push(BatcherType::CPU);
+        Ok(hashes)
+    }
+
+    fn max_batch_size(&self) -> usize {
+        match &self.primary {
+            Some(primary) => primary.max_batch_size(),
+            None => self.max_batch_size,
+        }
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        match &mut self.primary {
+            Some(primary) => primary.sync(),
+            None => Ok(()),
+        }
+    }
+
+    fn release(&mut self) -> Result<(), Error> {
+        match &mut self.primary {
+            Some(primary) => primary.release(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_device::{fake_backends, random_preimages_and_hashes, FakeOperation};
+    use generic_array::typenum::U8;
+
+    fn config() -> BatcherConfig {
+        BatcherConfig {
+            retry_count: 1,
+            ..BatcherConfig::new(64)
+        }
+    }
+
+    #[test]
+    fn test_fallback_batcher() {
+        let (preimages, expected) = random_preimages_and_hashes::<U8>(10);
+
+        let (control, factory) = fake_backends();
+        let device = Batcher::with_factory(
+            Strength::Standard,
+            &BatcherType::Custom,
+            &config(),
+            Some(&factory),
+        );
+        let mut batcher = FallbackBatcher::from_primary(
+            device,
+            BatcherType::Custom,
+            Strength::Standard,
+            &config(),
+        )
+        .unwrap();
+
+        assert_eq!(expected, batcher.hash(&preimages).unwrap());
+        assert_eq!(BatcherType::Custom, batcher.active());
+
+        // A failed batch is hashed again on the CPU, as are all later batches.
+        control.fail_next(
+            FakeOperation::Submit,
+            1,
+            Error::GPUError("injected".to_string()),
+        );
+        assert_eq!(expected, batcher.hash(&preimages).unwrap());
+        assert_eq!(expected, batcher.hash(&preimages).unwrap());
+        assert_eq!(BatcherType::CPU, batcher.active());
+        assert_eq!(2, control.calls(FakeOperation::Submit));
+        assert_eq!(
+            &[BatcherType::Custom, BatcherType::CPU, BatcherType::CPU],
+            batcher.batch_sources()
+        );
+        match batcher.fallback_reason() {
+            Some(Error::GPUError(_)) => (),
+            other => panic!("unexpected fallback reason: {:?}", other),
+        }
+
+        // Failing to create the device falls back immediately.
+        let mut batcher = FallbackBatcher::<U8>::from_primary(
+            Err(Error::GPUError("no device".to_string())),
+            BatcherType::GPU,
+            Strength::Standard,
+            &config(),
+        )
+        .unwrap();
+        assert_eq!(BatcherType::CPU, batcher.active());
+        assert_eq!(expected, batcher.hash(&preimages).unwrap());
+
+        // As does a device which cannot hash the arity.
+        let batcher = FallbackBatcher::<U8>::from_primary(
+            Err(Error::UnsupportedArity(8)),
+            BatcherType::GPU,
+            Strength::Standard,
+            &config(),
+        )
+        .unwrap();
+        assert_eq!(BatcherType::CPU, batcher.active());
+
+        // Errors which are not device failures are returned.
+        assert!(FallbackBatcher::<U8>::from_primary(
+            Err(Error::InvalidConfiguration("bad".to_string())),
+            BatcherType::GPU,
+            Strength::Standard,
+            &config(),
+        )
+        .is_err());
+    }
+}
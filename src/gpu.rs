@@ -1,16 +1,15 @@
+use crate::backend::{bytes_per_preimage, DeviceBatchHasher, HashBackend};
+use crate::batch_hasher::{BatcherConfig, MAX_KERNELS};
 use crate::error::Error;
+use crate::pool::{ContextLease, ContextPool};
 use crate::poseidon::PoseidonConstants;
-use crate::{Arity, BatchHasher, Strength, DEFAULT_STRENGTH};
+use crate::{Arity, Strength, DEFAULT_STRENGTH};
 use ff::{PrimeField, PrimeFieldDecodingError};
 use generic_array::{typenum, ArrayLength, GenericArray};
 use paired::bls12_381::{Bls12, Fr, FrRepr};
-use std::marker::PhantomData;
-use std::sync::Mutex;
 use triton::FutharkContext;
 use triton::{Array_u64_1d, Array_u64_2d, Array_u64_3d};
 use typenum::{U11, U2, U8};
-use std::env;
-use std::{thread, time};
 use log::info;
 
 /// Convenience type aliases for opaque pointers from the generated Futhark bindings.
@@ -22,43 +21,25 @@ type S2State = triton::FutharkOpaqueS2State;
 type S8State = triton::FutharkOpaqueS8State;
 type S11State = triton::FutharkOpaqueS11State;
 
+type GenericState = triton::FutharkOpaqueGenericState;
+
 pub(crate) type T864MState = triton::FutharkOpaqueT864MState;
 
+/// Number of leaves of the arity 8 tree built in one call by the whole-tree entry point: 64MiB of `Fr`s.
+const TREE8_64M_LEAVES: usize = (64 << 20) / std::mem::size_of::<Fr>();
+
+/// Device memory needed by the whole-tree entry point, for the leaves and the rows above them.
+const TREE8_64M_BYTES: usize =
+    (TREE8_64M_LEAVES + (TREE8_64M_LEAVES - 1) / 7) * std::mem::size_of::<Fr>();
+
+/// Arities for which the GPU kernels have specialized entry points. Every other arity is hashed by the generic
+/// entry point, with constants uploaded by `GPUConstants::upload`.
+pub const GPU_ARITIES: [usize; 3] = [2, 8, 11];
 
 lazy_static! {
-    static ref FUTHARK_CONTEXT_NEXT_INDEX: Mutex<usize> = Mutex::new(0);
-    pub static ref FUTHARK_CONTEXT_0: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_1: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_2: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_3: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_4: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_5: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_6: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_7: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_8: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_9: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_10: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_11: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_12: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_13: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_14: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_15: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_16: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_17: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_18: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_19: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_20: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_21: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_22: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_23: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_24: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_25: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_26: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_27: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_28: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_29: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_30: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
-    pub static ref FUTHARK_CONTEXT_31: Mutex<FutharkContext> = Mutex::new(FutharkContext::new());
+    /// Futhark contexts shared by all `FutharkBackend`s, created as they are needed.
+    pub static ref FUTHARK_CONTEXTS: ContextPool<FutharkContext> =
+        ContextPool::new(MAX_KERNELS, || Ok(FutharkContext::new()));
 }
 
 /// Container to hold the state corresponding to each supported arity.
@@ -69,25 +50,31 @@ enum BatcherState {
     Arity2s(S2State),
     Arity8s(S8State),
     Arity11s(S11State),
+    Generic { arity: usize, state: GenericState },
 }
 
 impl BatcherState {
     /// Create a new state for use in batch hashing preimages of `Arity` elements.
     /// State is an opaque pointer supplied to the corresponding GPU entry point when processing a batch.
-    fn new<A: Arity<Fr>>(ctx: &Mutex<FutharkContext>) -> Result<Self, Error> {
+    /// Returns `Error::UnsupportedArity` if `Arity` has no Poseidon constants.
+    fn new<A: Arity<Fr>>(ctx: &mut FutharkContext) -> Result<Self, Error> {
         Self::new_with_strength::<A>(ctx, DEFAULT_STRENGTH)
     }
     fn new_with_strength<A: Arity<Fr>>(
-        ctx: &Mutex<FutharkContext>,
+        ctx: &mut FutharkContext,
         strength: Strength,
     ) -> Result<Self, Error> {
-        let mut ctx = ctx.lock().unwrap();
-        Ok(match A::to_usize() {
-            size if size == 2 => init_hash2(&mut ctx, strength)?,
-            size if size == 8 => init_hash8(&mut ctx, strength)?,
-            size if size == 11 => init_hash11(&mut ctx, strength)?,
-            _ => panic!("unsupported arity: {}", A::to_usize()),
-        })
+        init_state::<A>(ctx, strength)
+    }
+
+    /// The arity of the preimages this state hashes.
+    fn arity(&self) -> usize {
+        match self {
+            BatcherState::Arity2(_) | BatcherState::Arity2s(_) => 2,
+            BatcherState::Arity8(_) | BatcherState::Arity8s(_) => 8,
+            BatcherState::Arity11(_) | BatcherState::Arity11s(_) => 11,
+            BatcherState::Generic { arity, .. } => *arity,
+        }
     }
 
     /// Hash a batch of N * `Arity` `Fr`s into N `Fr`s.
@@ -99,6 +86,13 @@ impl BatcherState {
     where
         A: Arity<Fr>,
     {
+        if self.arity() != A::to_usize() {
+            return Err(Error::ArityMismatch {
+                expected: self.arity(),
+                actual: A::to_usize(),
+            });
+        }
+
         match self {
             BatcherState::Arity2(state) => {
                 let (res, state) = mbatch_hash2(ctx, state, preimages)?;
@@ -124,172 +118,204 @@ impl BatcherState {
                 let (res, state) = mbatch_hash11s(ctx, state, preimages)?;
                 Ok((res, BatcherState::Arity11s(state)))
             }
+            BatcherState::Generic { arity, state } => {
+                let (res, state) = mbatch_hash_generic(ctx, state, preimages)?;
+                Ok((
+                    res,
+                    BatcherState::Generic {
+                        arity: *arity,
+                        state,
+                    },
+                ))
+            }
         }
     }
 }
 
 /// `GPUBatchHasher` implements `BatchHasher` and performs the batched hashing on GPU.
-pub struct GPUBatchHasher<'a, A> {
-    ctx: &'a Mutex<FutharkContext>,
-    state: BatcherState,
-    /// If `tree_builder_state` is provided, use it to build the final 64MiB tree on the GPU with one call.
+pub type GPUBatchHasher<A> = DeviceBatchHasher<FutharkBackend, A>;
+
+/// `FutharkBackend` is a `HashBackend` which hashes on a GPU, through a context leased from `FUTHARK_CONTEXTS`.
+pub struct FutharkBackend {
+    ctx: ContextLease<FutharkContext>,
+    state: Option<BatcherState>,
+    /// Whether the uploaded constants can build the 64MiB arity 8 tree on the GPU with one call.
+    whole_tree: bool,
+    /// State of the whole-tree entry point, set up by the first `build_tree`.
     tree_builder_state: Option<T864MState>,
+    /// Bytes of the leased context's memory which all its backends may reserve, if limited.
+    memory_budget: Option<usize>,
     max_batch_size: usize,
-    hash_retry: usize,
-    _a: PhantomData<A>,
+    /// Largest batch which fits the memory reserved in `init`, if the memory is limited.
+    reserved_batch_size: Option<usize>,
+    /// Whether the memory for the whole tree is reserved, so retried builds do not reserve it again.
+    tree_memory_reserved: bool,
 }
 
-impl<A> GPUBatchHasher<'_, A>
-where
-    A: Arity<Fr>,
-{
-    /// Create a new `GPUBatchHasher` and initialize it with state corresponding with its `A`.
-    pub(crate) fn new(max_batch_size: usize) -> Result<Self, Error> {
-        Self::new_with_strength(DEFAULT_STRENGTH, max_batch_size)
-    }
-
-    /// Create a new `GPUBatchHasher` and initialize it with state corresponding with its `A`.
-    pub(crate) fn new_with_strength(
-        strength: Strength,
-        max_batch_size: usize,
-    ) -> Result<Self, Error> {
-        let gpu_count = match env::var("GPU_COUNT") {
-            Ok(val) => {
-                match val.parse::<usize>() {
-                  Ok(n) => {
-                      info!("GPUBatchHasher find GPU_COUNT={} from env var", n);
-                      n
-                  },
-                  Err(_e) => 1,
-                }
-            },
-            Err(_e) => 1
-        };
-        
-        let kernel_per_gpu = match env::var("KERNEL_PER_GPU") {
-            Ok(val) => {
-                match val.parse::<usize>() {
-                  Ok(n) => {
-                      info!("GPUBatchHasher find KERNEL_PER_GPU={} from env var", n);
-                      n
-                  },
-                  Err(_e) => 4,
-                }
-            },
-            Err(_e) => 4
-        };
+impl FutharkBackend {
+    /// Lease the least-loaded of the first `config.kernel_count()` contexts for a new backend hashing arity `A`.
+    /// Returns `Error::UnsupportedArity`, without leasing a context, if `A` has no Poseidon constants.
+    pub fn new<A: Arity<Fr>>(config: &BatcherConfig) -> Result<Self, Error> {
+        config.validate()?;
+        check_arity::<A>()?;
 
-        // 4: Kernels per GPU.
-        let kernel_count = std::cmp::min(kernel_per_gpu*gpu_count, 32);
-
-        let mut next_index = FUTHARK_CONTEXT_NEXT_INDEX.lock().unwrap();
-        let index = *next_index % kernel_count;
-        *next_index += 1;
-        let ctx: &Mutex<FutharkContext> = match index {
-            0 => &*FUTHARK_CONTEXT_0,
-            1 => &*FUTHARK_CONTEXT_1,
-            2 => &*FUTHARK_CONTEXT_2,
-            3 => &*FUTHARK_CONTEXT_3,
-            4 => &*FUTHARK_CONTEXT_4,
-            5 => &*FUTHARK_CONTEXT_5,
-            6 => &*FUTHARK_CONTEXT_6,
-            7 => &*FUTHARK_CONTEXT_7,
-            8 => &*FUTHARK_CONTEXT_8,
-            9 => &*FUTHARK_CONTEXT_9,
-            10 => &*FUTHARK_CONTEXT_10,
-            11 => &*FUTHARK_CONTEXT_11,
-            12 => &*FUTHARK_CONTEXT_12,
-            13 => &*FUTHARK_CONTEXT_13,
-            14 => &*FUTHARK_CONTEXT_14,
-            15 => &*FUTHARK_CONTEXT_15,
-            16 => &*FUTHARK_CONTEXT_16,
-            17 => &*FUTHARK_CONTEXT_17,
-            18 => &*FUTHARK_CONTEXT_18,
-            19 => &*FUTHARK_CONTEXT_19,
-            20 => &*FUTHARK_CONTEXT_20,
-            21 => &*FUTHARK_CONTEXT_21,
-            22 => &*FUTHARK_CONTEXT_22,
-            23 => &*FUTHARK_CONTEXT_23,
-            24 => &*FUTHARK_CONTEXT_24,
-            25 => &*FUTHARK_CONTEXT_25,
-            26 => &*FUTHARK_CONTEXT_26,
-            27 => &*FUTHARK_CONTEXT_27,
-            28 => &*FUTHARK_CONTEXT_28,
-            29 => &*FUTHARK_CONTEXT_29,
-            30 => &*FUTHARK_CONTEXT_30,
-            31 => &*FUTHARK_CONTEXT_31,
-            _ => &*FUTHARK_CONTEXT_0
-        };
-        info!("Create GPUBatchHasher using the {}/{} FUTHARK_CONTEXT", index + 1, kernel_count);
-
-        let hash_retry = match env::var("P2_RETRY") {
-            Ok(val) => {
-                match val.parse::<usize>() {
-                  Ok(n) => {
-                      info!("GPUBatchHasher find P2_RETRY={} from env var", n);
-                      n
-                  },
-                  Err(_e) => 3600,
-                }
-            },
-            Err(_e) => 3600
-        };
+        let ctx = FUTHARK_CONTEXTS.lease_up_to(config.kernel_count())?;
+        info!(
+            "Create FutharkBackend using the {}/{} FUTHARK_CONTEXT",
+            ctx.index() + 1,
+            config.kernel_count()
+        );
 
         Ok(Self {
             ctx,
-            state: BatcherState::new_with_strength::<A>(ctx, strength)?,
+            state: None,
+            whole_tree: false,
             tree_builder_state: None,
-            max_batch_size,
-            hash_retry,
-            _a: PhantomData::<A>,
+            memory_budget: config.memory_budget,
+            max_batch_size: config.max_batch_size,
+            reserved_batch_size: None,
+            tree_memory_reserved: false,
         })
     }
-}
 
-impl<A> Drop for GPUBatchHasher<'_, A> {
-    fn drop(&mut self) {
-        info!("GPUBatchHasher Drop");
-        unsafe {
-            let ctx = self.ctx.lock().unwrap();
-            info!("futhark_context_sync");
-            let a = triton::bindings::futhark_context_sync(ctx.context);
-            info!("futhark_context_sync returns {}", a);
-            let b = triton::bindings::futhark_context_clear_caches(ctx.context);
-            info!("futhark_context_clear_caches returns {}", b);
+    /// Set up the whole-tree entry point, reserving the memory for the tree from the context's budget.
+    fn init_whole_tree(&mut self) -> Result<T864MState, Error> {
+        if let (Some(budget), false) = (self.memory_budget, self.tree_memory_reserved) {
+            if !self.ctx.try_reserve_memory(budget, TREE8_64M_BYTES) {
+                return Err(Error::GPUError(format!(
+                    "context {} has less than {} bytes of its memory budget left for the whole tree",
+                    self.ctx.index(),
+                    TREE8_64M_BYTES
+                )));
+            }
+            self.tree_memory_reserved = true;
         }
+
+        let mut ctx = self.ctx.lock();
+        init_tree8_64m(&mut ctx)
     }
 }
 
-impl<A> BatchHasher<A> for GPUBatchHasher<'_, A>
+impl<A> HashBackend<A> for FutharkBackend
 where
     A: Arity<Fr>,
 {
-    /// Hash a batch of `A`-sized preimages.
-    fn hash(&mut self, preimages: &[GenericArray<Fr, A>]) -> Result<Vec<Fr>, Error> {
-        let mut tries = self.hash_retry;
-        while tries > 0 {
-            {
-                let mut ctx = self.ctx.lock().unwrap();
-                match self.state.hash(&mut ctx, preimages) {
-                    Err(e) => {
-                        info!("Retry: {:?}", e);
-                    }
-                    Ok((res, state)) => {
-                        self.state = state;
-                        return Ok(res);
-                    }
-                }
+    /// Reserve memory for a full batch from the leased context's budget, or for as much of one as remains. The
+    /// context itself was created when it was leased.
+    fn init(&mut self) -> Result<(), Error> {
+        if let Some(budget) = self.memory_budget {
+            let per_preimage = bytes_per_preimage::<A>();
+            let granted = self
+                .ctx
+                .reserve_memory(budget, self.max_batch_size * per_preimage);
+            if granted < per_preimage {
+                self.ctx.release_memory();
+                return Err(Error::GPUError(format!(
+                    "context {} has less than {} bytes of its memory budget left",
+                    self.ctx.index(),
+                    per_preimage
+                )));
             }
-            let await_sec = time::Duration::from_secs(5 as u64);
-            tries -= 1;
-            info!("Retrying in 5 secs, retries left: {:?}", tries);
-            thread::sleep(await_sec);
+            self.reserved_batch_size = Some(granted / per_preimage);
         }
-        Err(Error::Other(format!("GPUBatchHasher errored after {:?} tries", self.hash_retry).to_string()))
+        Ok(())
+    }
+
+    fn max_batch_size(&self) -> Option<usize> {
+        self.reserved_batch_size
     }
 
-    fn max_batch_size(&self) -> usize {
-        self.max_batch_size
+    fn upload_constants(&mut self, strength: Strength) -> Result<(), Error> {
+        let mut ctx = self.ctx.lock();
+        self.state = Some(BatcherState::new_with_strength::<A>(&mut ctx, strength)?);
+
+        // The whole-tree entry point only exists for standard-strength arity 8 trees, and needs room for the tree.
+        // Its state is only set up when such a tree is built, since most trees are smaller.
+        let fits_budget = self
+            .memory_budget
+            .map_or(true, |budget| budget >= TREE8_64M_BYTES);
+        let standard = match strength {
+            Strength::Standard => true,
+            Strength::Strengthened => false,
+        };
+        self.whole_tree = A::to_usize() == 8 && fits_budget && standard;
+        Ok(())
+    }
+
+    /// Hash a batch of `A`-sized preimages.
+    fn submit(&mut self, preimages: &[GenericArray<Fr, A>]) -> Result<Vec<Fr>, Error> {
+        let state = self
+            .state
+            .as_mut()
+            .ok_or_else(|| Error::GPUError("constants have not been uploaded".to_string()))?;
+        let mut ctx = self.ctx.lock();
+
+        let (res, new_state) = state.hash(&mut ctx, preimages)?;
+        *state = new_state;
+        Ok(res)
+    }
+
+    fn whole_tree_leaves(&self) -> Option<usize> {
+        if self.whole_tree {
+            Some(TREE8_64M_LEAVES)
+        } else {
+            None
+        }
+    }
+
+    fn build_tree(&mut self, leaves: &[Fr]) -> Result<Vec<Fr>, Error> {
+        if !self.whole_tree || leaves.len() != TREE8_64M_LEAVES {
+            return Err(Error::GPUError(format!(
+                "no whole-tree entry point for {} leaves of arity {}",
+                leaves.len(),
+                A::to_usize()
+            )));
+        }
+        if self.tree_builder_state.is_none() {
+            self.tree_builder_state = Some(self.init_whole_tree()?);
+        }
+
+        let state = self
+            .tree_builder_state
+            .as_mut()
+            .expect("whole-tree state was just set up");
+        let mut ctx = self.ctx.lock();
+
+        let (res, new_state) = build_tree8_64m(&mut ctx, state, leaves)?;
+        *state = new_state;
+        Ok(res)
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        let ctx = self.ctx.lock();
+        match unsafe { triton::bindings::futhark_context_sync(ctx.context) } {
+            0 => Ok(()),
+            n => Err(Error::GPUError(format!(
+                "futhark_context_sync returned {}",
+                n
+            ))),
+        }
+    }
+
+    /// Free the state holding the constants, and the context's caches. The context itself is returned to
+    /// `FUTHARK_CONTEXTS` when the backend is dropped.
+    fn release(&mut self) -> Result<(), Error> {
+        info!("FutharkBackend release");
+        self.state = None;
+        self.whole_tree = false;
+        self.tree_builder_state = None;
+        self.tree_memory_reserved = false;
+        self.ctx.release_memory();
+        self.reserved_batch_size = None;
+
+        let ctx = self.ctx.lock();
+        match unsafe { triton::bindings::futhark_context_clear_caches(ctx.context) } {
+            0 => Ok(()),
+            n => Err(Error::GPUError(format!(
+                "futhark_context_clear_caches returned {}",
+                n
+            ))),
+        }
     }
 }
 
@@ -298,10 +324,30 @@ struct GPUConstants<A>(PoseidonConstants<Bls12, A>)
 where
     A: Arity<Fr>;
 
+/// The constants for one arity and strength, as arrays on the GPU, in the order the init entry points take them.
+struct GPUConstantArrays {
+    arity_tag: Array_u64_1d,
+    round_keys: Array_u64_2d,
+    mds_matrix: Array_u64_3d,
+    pre_sparse_matrix: Array_u64_3d,
+    sparse_matrixes: Array_u64_3d,
+}
+
 impl<A> GPUConstants<A>
 where
     A: Arity<Fr>,
 {
+    /// Flatten and upload all the constants.
+    fn upload(&self, ctx: &FutharkContext) -> Result<GPUConstantArrays, Error> {
+        Ok(GPUConstantArrays {
+            arity_tag: self.arity_tag(ctx)?,
+            round_keys: self.round_keys(ctx)?,
+            mds_matrix: self.mds_matrix(ctx)?,
+            pre_sparse_matrix: self.pre_sparse_matrix(ctx)?,
+            sparse_matrixes: self.sparse_matrixes(ctx)?,
+        })
+    }
+
     fn arity_tag(&self, ctx: &FutharkContext) -> Result<Array_u64_1d, Error> {
         let arity_tag = self.0.arity_tag;
         array_u64_1d_from_fr(ctx, arity_tag)
@@ -487,99 +533,95 @@ fn as_u64s<U: ArrayLength<Fr>>(vec: &[GenericArray<Fr, U>]) -> Vec<u64> {
     safely
 }
 
-fn init_hash2(ctx: &mut FutharkContext, strength: Strength) -> Result<BatcherState, Error> {
-    let constants = GPUConstants(PoseidonConstants::<Bls12, U2>::new_with_strength(strength));
-    match strength {
-        Strength::Standard => {
-            let state = ctx
-                .init2(
-                    constants.arity_tag(&ctx)?,
-                    constants.round_keys(&ctx)?,
-                    constants.mds_matrix(&ctx)?,
-                    constants.pre_sparse_matrix(&ctx)?,
-                    constants.sparse_matrixes(&ctx)?,
-                )
-                .map_err(|e| Error::GPUError(format!("{:?}", e)))?;
-            Ok(BatcherState::Arity2(state))
-        }
-        Strength::Strengthened => {
-            let state = ctx
-                .init2s(
-                    constants.arity_tag(&ctx)?,
-                    constants.round_keys(&ctx)?,
-                    constants.mds_matrix(&ctx)?,
-                    constants.pre_sparse_matrix(&ctx)?,
-                    constants.sparse_matrixes(&ctx)?,
-                )
-                .map_err(|e| Error::GPUError(format!("{:?}", e)))?;
-            Ok(BatcherState::Arity2s(state))
-        }
+/// Returns `Error::UnsupportedArity` if arity `A` has no Poseidon constants to upload, as for the dummy `U0`.
+/// The GPU kernels can hash every other arity.
+pub(crate) fn check_arity<A: Arity<Fr>>() -> Result<(), Error> {
+    let arity = A::to_usize();
+    if arity >= 2 {
+        Ok(())
+    } else {
+        Err(Error::UnsupportedArity(arity))
     }
 }
 
-fn init_hash8(ctx: &mut FutharkContext, strength: Strength) -> Result<BatcherState, Error> {
-    let constants = GPUConstants(PoseidonConstants::<Bls12, U8>::new_with_strength(strength));
-    match strength {
-        Strength::Standard => {
-            let state = ctx
-                .init8(
-                    constants.arity_tag(&ctx)?,
-                    constants.round_keys(&ctx)?,
-                    constants.mds_matrix(&ctx)?,
-                    constants.pre_sparse_matrix(&ctx)?,
-                    constants.sparse_matrixes(&ctx)?,
-                )
-                .map_err(|e| Error::GPUError(format!("{:?}", e)))?;
+/// Create the state for hashing preimages of arity `A` with `strength`, uploading its constants to the GPU. Arities
+/// without a specialized entry point use the generic one, which takes the width and rounds from the constants.
+fn init_state<A: Arity<Fr>>(
+    ctx: &mut FutharkContext,
+    strength: Strength,
+) -> Result<BatcherState, Error> {
+    check_arity::<A>()?;
+    let arity = A::to_usize();
+
+    let c = GPUConstants(PoseidonConstants::<Bls12, A>::new_with_strength(strength)).upload(ctx)?;
+    let (t, k, m, p, s) = (
+        c.arity_tag,
+        c.round_keys,
+        c.mds_matrix,
+        c.pre_sparse_matrix,
+        c.sparse_matrixes,
+    );
 
-            Ok(BatcherState::Arity8(state))
+    Ok(match (arity, strength) {
+        (2, Strength::Standard) => {
+            BatcherState::Arity2(ctx.init2(t, k, m, p, s).map_err(gpu_error)?)
         }
-        Strength::Strengthened => {
-            let state = ctx
-                .init8s(
-                    constants.arity_tag(&ctx)?,
-                    constants.round_keys(&ctx)?,
-                    constants.mds_matrix(&ctx)?,
-                    constants.pre_sparse_matrix(&ctx)?,
-                    constants.sparse_matrixes(&ctx)?,
-                )
-                .map_err(|e| Error::GPUError(format!("{:?}", e)))?;
-
-            Ok(BatcherState::Arity8s(state))
+        (2, Strength::Strengthened) => {
+            BatcherState::Arity2s(ctx.init2s(t, k, m, p, s).map_err(gpu_error)?)
         }
-    }
+        (8, Strength::Standard) => {
+            BatcherState::Arity8(ctx.init8(t, k, m, p, s).map_err(gpu_error)?)
+        }
+        (8, Strength::Strengthened) => {
+            BatcherState::Arity8s(ctx.init8s(t, k, m, p, s).map_err(gpu_error)?)
+        }
+        (11, Strength::Standard) => {
+            BatcherState::Arity11(ctx.init11(t, k, m, p, s).map_err(gpu_error)?)
+        }
+        (11, Strength::Strengthened) => {
+            BatcherState::Arity11s(ctx.init11s(t, k, m, p, s).map_err(gpu_error)?)
+        }
+        _ => BatcherState::Generic {
+            arity,
+            state: ctx.init_generic(t, k, m, p, s).map_err(gpu_error)?,
+        },
+    })
 }
 
-fn init_hash11(ctx: &mut FutharkContext, strength: Strength) -> Result<BatcherState, Error> {
-    let constants = GPUConstants(PoseidonConstants::<Bls12, U11>::new_with_strength(strength));
+fn gpu_error<E: std::fmt::Debug>(e: E) -> Error {
+    Error::GPUError(format!("{:?}", e))
+}
 
-    match strength {
-        Strength::Standard => {
-            let state = ctx
-                .init11(
-                    constants.arity_tag(&ctx)?,
-                    constants.round_keys(&ctx)?,
-                    constants.mds_matrix(&ctx)?,
-                    constants.pre_sparse_matrix(&ctx)?,
-                    constants.sparse_matrixes(&ctx)?,
-                )
-                .map_err(|e| Error::GPUError(format!("{:?}", e)))?;
+fn init_tree8_64m(ctx: &mut FutharkContext) -> Result<T864MState, Error> {
+    let c = GPUConstants(PoseidonConstants::<Bls12, U8>::new()).upload(ctx)?;
+
+    ctx.init_t8_64m(
+        c.arity_tag,
+        c.round_keys,
+        c.mds_matrix,
+        c.pre_sparse_matrix,
+        c.sparse_matrixes,
+    )
+    .map_err(gpu_error)
+}
 
-            Ok(BatcherState::Arity11(state))
-        }
-        Strength::Strengthened => {
-            let state = ctx
-                .init11s(
-                    constants.arity_tag(&ctx)?,
-                    constants.round_keys(&ctx)?,
-                    constants.mds_matrix(&ctx)?,
-                    constants.pre_sparse_matrix(&ctx)?,
-                    constants.sparse_matrixes(&ctx)?,
-                )
-                .map_err(|e| Error::GPUError(format!("{:?}", e)))?;
-
-            Ok(BatcherState::Arity11s(state))
-        }
-    }
+/// Build the arity 8 tree with base row `leaves`, returning the rows above the base.
+fn build_tree8_64m(
+    ctx: &mut FutharkContext,
+    state: &T864MState,
+    leaves: &[Fr],
+) -> Result<(Vec<Fr>, T864MState), Error> {
+    assert_eq!(TREE8_64M_LEAVES, leaves.len());
+    let flat_leaves = frs_as_mont_u64s(leaves);
+    let input = Array_u64_1d::from_vec(*ctx, &flat_leaves, &[flat_leaves.len() as i64, 1])
+        .map_err(|_| Error::Other("could not convert".to_string()))?;
+
+    let (res, state) = ctx.build_tree8_64m(state, input).map_err(gpu_error)?;
+
+    let (vec, _shape) = res.to_vec();
+    let frs = unpack_fr_array_from_monts(vec.as_slice())?;
+
+    Ok((frs.to_vec(), state))
 }
 
 fn mbatch_hash2<A>(
@@ -720,6 +762,28 @@ where
     Ok((frs.to_vec(), state))
 }
 
+fn mbatch_hash_generic<A>(
+    ctx: &mut FutharkContext,
+    state: &GenericState,
+    preimages: &[GenericArray<Fr, A>],
+) -> Result<(Vec<Fr>, GenericState), Error>
+where
+    A: Arity<Fr>,
+{
+    let flat_preimages = as_mont_u64s(preimages);
+    let input = Array_u64_1d::from_vec(*ctx, &flat_preimages, &[flat_preimages.len() as i64, 1])
+        .map_err(|_| Error::Other("could not convert".to_string()))?;
+
+    let (res, state) = ctx
+        .mbatch_hash_generic(state, input)
+        .map_err(|e| Error::GPUError(format!("{:?}", e)))?;
+
+    let (vec, _shape) = res.to_vec();
+    let frs = unpack_fr_array_from_monts(vec.as_slice())?;
+
+    Ok((frs.to_vec(), state))
+}
+
 fn u64_vec<'a, U: ArrayLength<Fr>>(vec: &'a [GenericArray<Fr, U>]) -> Vec<u64> {
     vec![0; vec.len() * U::to_usize() * std::mem::size_of::<Fr>()]
 }
@@ -740,16 +804,22 @@ mod tests {
     fn test_mbatch_hash2() {
         let mut rng = XorShiftRng::from_seed(crate::TEST_SEED);
         let mut ctx = FutharkContext::new();
-        let mut state =
-            if let BatcherState::Arity2(s) = init_hash2(&mut ctx, Strength::Standard).unwrap() {
-                s
-            } else {
-                panic!("expected Arity2");
-            };
+        let mut state = if let BatcherState::Arity2(s) =
+            init_state::<U2>(&mut ctx, Strength::Standard).unwrap()
+        {
+            s
+        } else {
+            panic!("expected Arity2");
+        };
         let batch_size = 100;
+        let config = BatcherConfig::new(batch_size);
 
-        let mut gpu_hasher =
-            GPUBatchHasher::<U2>::new_with_strength(Strength::Standard, batch_size).unwrap();
+        let mut gpu_hasher = GPUBatchHasher::<U2>::new(
+            FutharkBackend::new::<U2>(&config).unwrap(),
+            Strength::Standard,
+            &config,
+        )
+        .unwrap();
         let mut simple_hasher =
             SimplePoseidonBatchHasher::<U2>::new_with_strength(Strength::Standard, batch_size)
                 .unwrap();
@@ -771,16 +841,21 @@ mod tests {
         let mut rng = XorShiftRng::from_seed(crate::TEST_SEED);
         let mut ctx = FutharkContext::new();
         let mut state = if let BatcherState::Arity2s(s) =
-            init_hash2(&mut ctx, Strength::Strengthened).unwrap()
+            init_state::<U2>(&mut ctx, Strength::Strengthened).unwrap()
         {
             s
         } else {
             panic!("expected Arity2s");
         };
         let batch_size = 100;
+        let config = BatcherConfig::new(batch_size);
 
-        let mut gpu_hasher =
-            GPUBatchHasher::<U2>::new_with_strength(Strength::Strengthened, batch_size).unwrap();
+        let mut gpu_hasher = GPUBatchHasher::<U2>::new(
+            FutharkBackend::new::<U2>(&config).unwrap(),
+            Strength::Strengthened,
+            &config,
+        )
+        .unwrap();
         let mut simple_hasher =
             SimplePoseidonBatchHasher::<U2>::new_with_strength(Strength::Strengthened, batch_size)
                 .unwrap();
@@ -801,16 +876,22 @@ mod tests {
     fn test_mbatch_hash8() {
         let mut rng = XorShiftRng::from_seed(crate::TEST_SEED);
         let mut ctx = FutharkContext::new();
-        let mut state =
-            if let BatcherState::Arity8(s) = init_hash8(&mut ctx, Strength::Standard).unwrap() {
-                s
-            } else {
-                panic!("expected Arity8");
-            };
+        let mut state = if let BatcherState::Arity8(s) =
+            init_state::<U8>(&mut ctx, Strength::Standard).unwrap()
+        {
+            s
+        } else {
+            panic!("expected Arity8");
+        };
         let batch_size = 100;
+        let config = BatcherConfig::new(batch_size);
 
-        let mut gpu_hasher =
-            GPUBatchHasher::<U8>::new_with_strength(Strength::Standard, batch_size).unwrap();
+        let mut gpu_hasher = GPUBatchHasher::<U8>::new(
+            FutharkBackend::new::<U8>(&config).unwrap(),
+            Strength::Standard,
+            &config,
+        )
+        .unwrap();
         let mut simple_hasher =
             SimplePoseidonBatchHasher::<U8>::new_with_strength(Strength::Standard, batch_size)
                 .unwrap();
@@ -832,16 +913,21 @@ mod tests {
         let mut rng = XorShiftRng::from_seed(crate::TEST_SEED);
         let mut ctx = FutharkContext::new();
         let mut state = if let BatcherState::Arity8s(s) =
-            init_hash8(&mut ctx, Strength::Strengthened).unwrap()
+            init_state::<U8>(&mut ctx, Strength::Strengthened).unwrap()
         {
             s
         } else {
             panic!("expected Arity8s");
         };
         let batch_size = 100;
+        let config = BatcherConfig::new(batch_size);
 
-        let mut gpu_hasher =
-            GPUBatchHasher::<U8>::new_with_strength(Strength::Strengthened, batch_size).unwrap();
+        let mut gpu_hasher = GPUBatchHasher::<U8>::new(
+            FutharkBackend::new::<U8>(&config).unwrap(),
+            Strength::Strengthened,
+            &config,
+        )
+        .unwrap();
         let mut simple_hasher =
             SimplePoseidonBatchHasher::<U8>::new_with_strength(Strength::Strengthened, batch_size)
                 .unwrap();
@@ -862,16 +948,22 @@ mod tests {
     fn test_mbatch_hash11() {
         let mut rng = XorShiftRng::from_seed(crate::TEST_SEED);
         let mut ctx = FutharkContext::new();
-        let mut state =
-            if let BatcherState::Arity11(s) = init_hash11(&mut ctx, Strength::Standard).unwrap() {
-                s
-            } else {
-                panic!("expected Arity11");
-            };
+        let mut state = if let BatcherState::Arity11(s) =
+            init_state::<U11>(&mut ctx, Strength::Standard).unwrap()
+        {
+            s
+        } else {
+            panic!("expected Arity11");
+        };
         let batch_size = 100;
+        let config = BatcherConfig::new(batch_size);
 
-        let mut gpu_hasher =
-            GPUBatchHasher::<U11>::new_with_strength(Strength::Standard, batch_size).unwrap();
+        let mut gpu_hasher = GPUBatchHasher::<U11>::new(
+            FutharkBackend::new::<U11>(&config).unwrap(),
+            Strength::Standard,
+            &config,
+        )
+        .unwrap();
         let mut simple_hasher =
             SimplePoseidonBatchHasher::<U11>::new_with_strength(Strength::Standard, batch_size)
                 .unwrap();
@@ -893,16 +985,21 @@ mod tests {
         let mut rng = XorShiftRng::from_seed(crate::TEST_SEED);
         let mut ctx = FutharkContext::new();
         let mut state = if let BatcherState::Arity11s(s) =
-            init_hash11(&mut ctx, Strength::Strengthened).unwrap()
+            init_state::<U11>(&mut ctx, Strength::Strengthened).unwrap()
         {
             s
         } else {
             panic!("expected Arity11s");
         };
         let batch_size = 100;
+        let config = BatcherConfig::new(batch_size);
 
-        let mut gpu_hasher =
-            GPUBatchHasher::<U11>::new_with_strength(Strength::Strengthened, batch_size).unwrap();
+        let mut gpu_hasher = GPUBatchHasher::<U11>::new(
+            FutharkBackend::new::<U11>(&config).unwrap(),
+            Strength::Strengthened,
+            &config,
+        )
+        .unwrap();
         let mut simple_hasher =
             SimplePoseidonBatchHasher::<U11>::new_with_strength(Strength::Strengthened, batch_size)
                 .unwrap();
@@ -918,4 +1015,55 @@ mod tests {
         assert_eq!(expected_hashes, hashes);
         assert_eq!(expected_hashes, gpu_hashes);
     }
+
+    #[test]
+    fn test_mbatch_hash_generic() {
+        use typenum::U4;
+
+        let mut rng = XorShiftRng::from_seed(crate::TEST_SEED);
+        let batch_size = 100;
+        let config = BatcherConfig::new(batch_size);
+
+        // Arity 4 has no specialized entry point.
+        for strength in &[Strength::Standard, Strength::Strengthened] {
+            let mut gpu_hasher = GPUBatchHasher::<U4>::new(
+                FutharkBackend::new::<U4>(&config).unwrap(),
+                *strength,
+                &config,
+            )
+            .unwrap();
+            let mut simple_hasher =
+                SimplePoseidonBatchHasher::<U4>::new_with_strength(*strength, batch_size).unwrap();
+
+            let preimages = (0..batch_size)
+                .map(|_| GenericArray::<Fr, U4>::generate(|_| Fr::random(&mut rng)))
+                .collect::<Vec<_>>();
+
+            let gpu_hashes = gpu_hasher.hash(&preimages).unwrap();
+            let expected_hashes: Vec<_> = simple_hasher.hash(&preimages).unwrap();
+            gpu_hasher.release().unwrap();
+
+            assert_eq!(expected_hashes, gpu_hashes);
+        }
+    }
+
+    #[test]
+    fn test_unsupported_arity() {
+        use crate::batch_hasher::{Batcher, BatcherType};
+
+        // The arity is checked before a context is leased, so no GPU is needed.
+        let config = BatcherConfig::new(100);
+        match FutharkBackend::new::<typenum::U0>(&config) {
+            Err(Error::UnsupportedArity(0)) => (),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("arity 0 should not be supported"),
+        }
+
+        // GPU batchers are not silently created on the CPU instead.
+        match Batcher::<typenum::U0>::new(&BatcherType::GPU, &config) {
+            Err(Error::UnsupportedArity(0)) => (),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("arity 0 should not be supported"),
+        }
+    }
 }
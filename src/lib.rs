@@ -11,11 +11,19 @@ use generic_array::GenericArray;
 pub use paired::bls12_381::Fr as Scalar;
 use paired::bls12_381::FrRepr;
 
+/// Hashing of bit and byte strings
+pub mod bytes;
 /// Poseidon circuit
 pub mod circuit;
+/// Circuit cost reporting
+pub mod cost;
+/// Poseidon authenticated encryption
+pub mod encryption;
 pub mod error;
 mod matrix;
 mod mds;
+/// Poseidon permutation as Plonkish gates
+pub mod plonk;
 
 /// Poseidon hash
 pub mod poseidon;
@@ -23,21 +31,52 @@ mod poseidon_alt;
 mod preprocessing;
 mod round_constants;
 
+/// Fiat–Shamir transcripts
+pub mod transcript;
+
 /// Tree Builder
-#[cfg(feature = "gpu")]
 pub mod tree_builder;
 
 /// Column Tree Builder
-#[cfg(feature = "gpu")]
 pub mod column_tree_builder;
 
+/// Pipelined Column Tree Builder
+pub mod column_tree_pipeline;
+
 #[cfg(feature = "gpu")]
 mod gpu;
 
 /// Batch Hasher
-#[cfg(feature = "gpu")]
 pub mod batch_hasher;
 
+/// Pluggable device backends for batch hashing
+pub mod backend;
+
+/// Simulated device backend, for testing without a GPU
+#[cfg(any(test, feature = "test-support"))]
+pub mod fake_device;
+
+/// Pool of device contexts shared by batch hashers
+pub mod pool;
+
+/// Retry policies for batch hashers
+pub mod retry;
+
+/// CPU fallback for failing devices
+pub mod fallback;
+
+/// Splitting batches across several batch hashers
+pub mod split;
+
+/// CPU verification of device batch hashes
+pub mod verify;
+
+/// Progress reporting and cancellation for tree builders
+pub mod progress;
+
+/// Checkpointing of tree builds
+pub mod checkpoint;
+
 pub(crate) const TEST_SEED: [u8; 16] = [
     0x59, 0x62, 0xbe, 0x5d, 0x76, 0x3d, 0x31, 0x8d, 0x17, 0xdb, 0x37, 0x32, 0x54, 0x06, 0xbc, 0xe5,
 ];
@@ -76,6 +115,18 @@ where
     fn max_batch_size(&self) -> usize {
         700000
     }
+
+    /// Wait until all batches hashed so far are complete on the device. Batch hashers without a device return at
+    /// once.
+    fn sync(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Release the device resources held by the batch hasher, which must not be used afterwards. Batch hashers
+    /// without a device return at once.
+    fn release(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
 }
 
 // Round numbers for supported arities. Not all arities have been supplied.
@@ -0,0 +1,273 @@
+//! The Poseidon permutation as custom arithmetic gates, for Plonk-style (Plonkish) proof systems.
+//!
+//! The permutation is laid out in `width` advice columns, one row per round, followed by a row holding the output.
+//! Row `r` holds the state before round `r`, and its gate constrains the next row to be the result of the round:
+//!
+//! ```text
+//! s_j = a_j + c_j
+//! t_j = s_j + q_j * (s_j^5 - s_j)
+//! q_linear * (a'_j - sum_i(t_i * M[i][j])) = 0
+//! ```
+//!
+//! where `a` and `a'` are the current and next rows, `c` are the round's constants (one fixed column per element),
+//! and `M` is the MDS matrix. The s-box selector `q_j` is `q_full + q_partial` for the first element and `q_full` for
+//! the rest, so full rounds apply the s-box to every element and partial rounds only to the first. `q_linear`
+//! enables the gate on every row but the last. The gate has degree 6.
+//!
+//! The layout uses the unoptimized round constants and MDS matrix, so every row of a witness is the canonical state
+//! (see `poseidon::Poseidon::permute`) between rounds.
+use crate::matrix::{apply_matrix, Matrix};
+use crate::poseidon::{Arity, PoseidonConstants};
+use crate::quintic_s_box;
+use ff::{Field, ScalarEngine};
+
+/// The fixed (selector and constant) columns of one row.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FixedRow<E: ScalarEngine> {
+    /// Apply the s-box to every element.
+    pub q_full: E::Fr,
+    /// Apply the s-box to the first element only.
+    pub q_partial: E::Fr,
+    /// Enforce the round's linear layer.
+    pub q_linear: E::Fr,
+    /// Round constants, added to each element before the s-box.
+    pub round_constants: Vec<E::Fr>,
+}
+
+/// The gate layout of the Poseidon permutation for one set of `PoseidonConstants`.
+#[derive(Clone, Debug)]
+pub struct PermutationLayout<E: ScalarEngine> {
+    width: usize,
+    fixed: Vec<FixedRow<E>>,
+    mds: Matrix<E::Fr>,
+}
+
+/// The advice columns of a permutation: one row per round, then the output.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Witness<E: ScalarEngine> {
+    pub rows: Vec<Vec<E::Fr>>,
+}
+
+/// A gate which is not satisfied by a witness.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GateFailure {
+    pub row: usize,
+    /// The element of the next row which does not match.
+    pub column: usize,
+}
+
+impl<E: ScalarEngine> PermutationLayout<E> {
+    pub fn new<A: Arity<E::Fr>>(constants: &PoseidonConstants<E, A>) -> Self {
+        let width = constants.width();
+        let rounds = constants.full_rounds + constants.partial_rounds;
+        let (zero, one) = (E::Fr::zero(), E::Fr::one());
+
+        let mut fixed: Vec<FixedRow<E>> = constants
+            .round_constants
+            .chunks(width)
+            .take(rounds)
+            .enumerate()
+            .map(|(round, round_constants)| {
+                let partial = round >= constants.half_full_rounds
+                    && round < constants.half_full_rounds + constants.partial_rounds;
+                FixedRow {
+                    q_full: if partial { zero } else { one },
+                    q_partial: if partial { one } else { zero },
+                    q_linear: one,
+                    round_constants: round_constants.to_vec(),
+                }
+            })
+            .collect();
+        assert_eq!(rounds, fixed.len(), "Not enough round constants");
+
+        // The output row is not constrained by a gate of its own.
+        fixed.push(FixedRow {
+            q_full: zero,
+            q_partial: zero,
+            q_linear: zero,
+            round_constants: vec![zero; width],
+        });
+
+        Self {
+            width,
+            fixed,
+            mds: constants.mds_matrices.m.clone(),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Number of rows, including the output row.
+    pub fn rows(&self) -> usize {
+        self.fixed.len()
+    }
+
+    pub fn fixed_row(&self, row: usize) -> &FixedRow<E> {
+        &self.fixed[row]
+    }
+
+    pub fn mds_matrix(&self) -> &Matrix<E::Fr> {
+        &self.mds
+    }
+
+    /// Generate the witness for permuting `state`. The last row of the witness is the permuted state.
+    pub fn generate_witness(&self, state: &[E::Fr]) -> Witness<E> {
+        assert_eq!(self.width, state.len(), "Invalid state size");
+
+        let mut rows = Vec::with_capacity(self.rows());
+        rows.push(state.to_vec());
+
+        for fixed in &self.fixed[..self.rows() - 1] {
+            let mut elements = rows.last().expect("rows cannot be empty").clone();
+            for (j, element) in elements.iter_mut().enumerate() {
+                if Self::s_box_selector(fixed, j) == E::Fr::one() {
+                    quintic_s_box::<E>(element, Some(&fixed.round_constants[j]), None);
+                } else {
+                    element.add_assign(&fixed.round_constants[j]);
+                }
+            }
+            rows.push(apply_matrix::<E>(&self.mds, &elements));
+        }
+
+        Witness { rows }
+    }
+
+    /// Evaluate the gate of `row`, given the `current` and `next` rows of a witness. Each element of the result is
+    /// zero if and only if the corresponding element of `next` is constrained correctly.
+    pub fn evaluate_gate(&self, row: usize, current: &[E::Fr], next: &[E::Fr]) -> Vec<E::Fr> {
+        assert_eq!(self.width, current.len(), "Invalid row size");
+        assert_eq!(self.width, next.len(), "Invalid row size");
+        let fixed = &self.fixed[row];
+
+        let t: Vec<E::Fr> = current
+            .iter()
+            .enumerate()
+            .map(|(j, a)| {
+                let mut s = *a;
+                s.add_assign(&fixed.round_constants[j]);
+
+                let mut s5_minus_s = s;
+                quintic_s_box::<E>(&mut s5_minus_s, None, None);
+                s5_minus_s.sub_assign(&s);
+                s5_minus_s.mul_assign(&Self::s_box_selector(fixed, j));
+
+                s.add_assign(&s5_minus_s);
+                s
+            })
+            .collect();
+        let linear = apply_matrix::<E>(&self.mds, &t);
+
+        next.iter()
+            .zip(linear.iter())
+            .map(|(a_next, l)| {
+                let mut value = *a_next;
+                value.sub_assign(l);
+                value.mul_assign(&fixed.q_linear);
+                value
+            })
+            .collect()
+    }
+
+    /// Check every gate against `witness`, as a prover would need to satisfy them, returning all failures.
+    pub fn verify(&self, witness: &Witness<E>) -> Result<(), Vec<GateFailure>> {
+        assert_eq!(self.rows(), witness.rows.len(), "Invalid witness size");
+
+        let failures: Vec<GateFailure> = witness
+            .rows
+            .windows(2)
+            .enumerate()
+            .flat_map(|(row, rows)| {
+                self.evaluate_gate(row, &rows[0], &rows[1])
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(_, value)| !value.is_zero())
+                    .map(move |(column, _)| GateFailure { row, column })
+            })
+            .collect();
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(failures)
+        }
+    }
+
+    fn s_box_selector(fixed: &FixedRow<E>, column: usize) -> E::Fr {
+        let mut q = fixed.q_full;
+        if column == 0 {
+            q.add_assign(&fixed.q_partial);
+        }
+        q
+    }
+}
+
+impl<E: ScalarEngine> Witness<E> {
+    /// The permuted state.
+    pub fn output(&self) -> &[E::Fr] {
+        self.rows.last().expect("witness cannot be empty")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::poseidon::{permute, Poseidon};
+    use crate::{scalar_from_u64, Strength};
+    use generic_array::typenum::{U2, U4, U8};
+    use paired::bls12_381::{Bls12, Fr};
+
+    #[test]
+    fn test_plonk_permutation() {
+        test_plonk_permutation_aux::<U2>(Strength::Standard);
+        test_plonk_permutation_aux::<U4>(Strength::Standard);
+        test_plonk_permutation_aux::<U8>(Strength::Strengthened);
+    }
+
+    fn test_plonk_permutation_aux<A: Arity<Fr>>(strength: Strength) {
+        let constants = PoseidonConstants::<Bls12, A>::new_with_strength(strength);
+        let layout = PermutationLayout::new(&constants);
+        assert_eq!(
+            constants.full_rounds + constants.partial_rounds + 1,
+            layout.rows()
+        );
+
+        let state: Vec<Fr> = (0..constants.width())
+            .map(|n| scalar_from_u64::<Fr>(5 * n as u64 + 2))
+            .collect();
+        let witness = layout.generate_witness(&state);
+        assert_eq!(Ok(()), layout.verify(&witness));
+
+        let mut expected = state.clone();
+        permute(&constants, &mut expected);
+        assert_eq!(&expected[..], witness.output());
+
+        // Hashing is the permutation of the tagged preimage.
+        let mut p = Poseidon::<Bls12, A>::new_with_preimage(&state[1..], &constants);
+        let mut tagged = state.clone();
+        tagged[0] = constants.arity_tag;
+        assert_eq!(p.hash(), layout.generate_witness(&tagged).output()[1]);
+
+        // Changing a cell breaks the gates on either side of it: the previous row's (for every column of a full
+        // round) and its own (for every column its s-box result feeds).
+        let mut tampered = witness.clone();
+        tampered.rows[1][1].add_assign(&Fr::one());
+        let failures = layout.verify(&tampered).unwrap_err();
+        assert!(failures.contains(&GateFailure { row: 0, column: 1 }));
+        assert!(failures.iter().all(|f| f.row <= 1));
+        assert!(failures.iter().any(|f| f.row == 1));
+
+        // Tampering with the output is caught by the last round's gate.
+        let mut tampered = witness.clone();
+        let last = layout.rows() - 1;
+        tampered.rows[last][0].add_assign(&Fr::one());
+        assert_eq!(
+            Err(vec![GateFailure {
+                row: last - 1,
+                column: 0
+            }]),
+            layout.verify(&tampered)
+        );
+    }
+}
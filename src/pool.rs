@@ -0,0 +1,344 @@
+use crate::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// `ContextPool` shares device contexts among the hashers which use them.
+///
+/// Contexts are created on demand, up to the pool's limit: a lease gets a new context if every existing one is
+/// leased, and the limit has not been reached. Otherwise it gets the least-loaded context, i.e. the one with the
+/// fewest outstanding leases. A lease is returned when it is dropped. Leases of the same context are serialized by
+/// `ContextLease::lock`, which records how long each caller waited.
+///
+/// Leases of the same context also share its memory: see `ContextLease::reserve_memory`.
+///
+/// Clones share the same contexts.
+pub struct ContextPool<C> {
+    inner: Arc<PoolInner<C>>,
+}
+
+struct PoolInner<C> {
+    limit: usize,
+    factory: Box<dyn Fn() -> Result<C, Error> + Send + Sync>,
+    slots: Mutex<Vec<Arc<Slot<C>>>>,
+    metrics: Mutex<PoolMetrics>,
+}
+
+struct Slot<C> {
+    index: usize,
+    context: Mutex<C>,
+    leases: AtomicUsize,
+    /// Bytes of the context's memory reserved by its leases.
+    reserved: Mutex<usize>,
+}
+
+/// Usage statistics of a `ContextPool`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PoolMetrics {
+    /// Number of contexts created.
+    pub contexts: usize,
+    /// Number of leases granted.
+    pub leases: usize,
+    /// Number of leases not yet returned.
+    pub active_leases: usize,
+    /// Number of times a leased context was locked.
+    pub locks: usize,
+    /// Total time spent waiting to lock leased contexts.
+    pub total_wait: Duration,
+    /// Longest time spent waiting to lock a leased context.
+    pub max_wait: Duration,
+}
+
+/// A lease of one of a `ContextPool`'s contexts, returned to the pool when dropped.
+pub struct ContextLease<C> {
+    pool: ContextPool<C>,
+    slot: Arc<Slot<C>>,
+    /// Bytes of the context's memory reserved by this lease.
+    reserved: usize,
+}
+
+impl<C> Clone for ContextPool<C> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<C> ContextPool<C> {
+    /// Create a pool of at most `limit` contexts, each created by `factory` when first needed.
+    pub fn new<F>(limit: usize, factory: F) -> Self
+    where
+        F: Fn() -> Result<C, Error> + Send + Sync + 'static,
+    {
+        assert!(limit > 0, "ContextPool must allow at least one context");
+
+        Self {
+            inner: Arc::new(PoolInner {
+                limit,
+                factory: Box::new(factory),
+                slots: Mutex::new(Vec::new()),
+                metrics: Mutex::new(Default::default()),
+            }),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.inner.limit
+    }
+
+    /// Lease a context, creating one if every existing context is leased and the limit allows.
+    pub fn lease(&self) -> Result<ContextLease<C>, Error> {
+        self.lease_up_to(self.inner.limit)
+    }
+
+    /// Lease one of the first `max_contexts` contexts (or of all contexts, if the pool's limit is lower), creating
+    /// one if all of those are leased and there are fewer than `max_contexts`.
+    pub fn lease_up_to(&self, max_contexts: usize) -> Result<ContextLease<C>, Error> {
+        let max_contexts = usize::min(max_contexts, self.inner.limit);
+        if max_contexts == 0 {
+            return Err(Error::InvalidConfiguration(
+                "cannot lease from zero contexts".to_string(),
+            ));
+        }
+
+        let mut slots = self.inner.slots.lock().unwrap();
+        let least_loaded = slots
+            .iter()
+            .take(max_contexts)
+            .min_by_key(|slot| slot.leases.load(Ordering::SeqCst))
+            .cloned();
+
+        let slot = match least_loaded {
+            Some(ref slot)
+                if slot.leases.load(Ordering::SeqCst) == 0 || slots.len() >= max_contexts =>
+            {
+                slot.clone()
+            }
+            _ => {
+                let slot = Arc::new(Slot {
+                    index: slots.len(),
+                    context: Mutex::new((self.inner.factory)()?),
+                    leases: AtomicUsize::new(0),
+                    reserved: Mutex::new(0),
+                });
+                slots.push(slot.clone());
+                self.inner.metrics.lock().unwrap().contexts += 1;
+                slot
+            }
+        };
+        slot.leases.fetch_add(1, Ordering::SeqCst);
+
+        let mut metrics = self.inner.metrics.lock().unwrap();
+        metrics.leases += 1;
+        metrics.active_leases += 1;
+
+        Ok(ContextLease {
+            pool: self.clone(),
+            slot,
+            reserved: 0,
+        })
+    }
+
+    /// Number of outstanding leases of each context, by index.
+    pub fn loads(&self) -> Vec<usize> {
+        self.inner
+            .slots
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|slot| slot.leases.load(Ordering::SeqCst))
+            .collect()
+    }
+
+    /// Bytes of memory reserved by the leases of each context, by index.
+    pub fn reserved_memory(&self) -> Vec<usize> {
+        self.inner
+            .slots
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|slot| *slot.reserved.lock().unwrap())
+            .collect()
+    }
+
+    pub fn metrics(&self) -> PoolMetrics {
+        self.inner.metrics.lock().unwrap().clone()
+    }
+}
+
+impl<C> ContextLease<C> {
+    /// Index of the leased context in its pool.
+    pub fn index(&self) -> usize {
+        self.slot.index
+    }
+
+    /// Lock the leased context for exclusive use, waiting for any other lease holder using it.
+    pub fn lock(&self) -> MutexGuard<C> {
+        let start = Instant::now();
+        let guard = self.slot.context.lock().unwrap();
+        let wait = start.elapsed();
+
+        let mut metrics = self.pool.inner.metrics.lock().unwrap();
+        metrics.locks += 1;
+        metrics.total_wait += wait;
+        metrics.max_wait = Duration::max(metrics.max_wait, wait);
+
+        guard
+    }
+
+    /// Reserve up to `wanted` bytes of the leased context's memory, of which the leases of the context may reserve
+    /// `budget` in total. Returns the number of bytes granted, which is less than `wanted` if the rest of the budget
+    /// is reserved by other leases. Reservations are returned by `release_memory`, or when the lease is dropped.
+    pub fn reserve_memory(&mut self, budget: usize, wanted: usize) -> usize {
+        let mut reserved = self.slot.reserved.lock().unwrap();
+        let granted = usize::min(wanted, budget.saturating_sub(*reserved));
+        *reserved += granted;
+        self.reserved += granted;
+        granted
+    }
+
+    /// Reserve exactly `wanted` bytes as by `reserve_memory`, or nothing if less of `budget` remains. Returns whether
+    /// the memory was reserved.
+    pub fn try_reserve_memory(&mut self, budget: usize, wanted: usize) -> bool {
+        let mut reserved = self.slot.reserved.lock().unwrap();
+        if budget.saturating_sub(*reserved) < wanted {
+            return false;
+        }
+        *reserved += wanted;
+        self.reserved += wanted;
+        true
+    }
+
+    /// Return all the memory reserved by this lease to its context.
+    pub fn release_memory(&mut self) {
+        *self.slot.reserved.lock().unwrap() -= self.reserved;
+        self.reserved = 0;
+    }
+
+    /// Bytes of the context's memory reserved by this lease.
+    pub fn reserved_memory(&self) -> usize {
+        self.reserved
+    }
+}
+
+impl<C> Drop for ContextLease<C> {
+    fn drop(&mut self) {
+        self.release_memory();
+        self.slot.leases.fetch_sub(1, Ordering::SeqCst);
+        self.pool.inner.metrics.lock().unwrap().active_leases -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    /// A mock context, numbered in order of creation.
+    struct MockContext(usize);
+
+    fn mock_pool(limit: usize) -> ContextPool<MockContext> {
+        let created = AtomicUsize::new(0);
+        ContextPool::new(limit, move || {
+            Ok(MockContext(created.fetch_add(1, Ordering::SeqCst)))
+        })
+    }
+
+    #[test]
+    fn test_context_pool_leases() {
+        let pool = mock_pool(3);
+
+        // Contexts are created on demand, while every existing context is leased.
+        let a = pool.lease().unwrap();
+        let b = pool.lease().unwrap();
+        assert_eq!((0, 1), (a.index(), b.index()));
+        assert_eq!(1, b.lock().0);
+        drop(a);
+
+        // A returned context is reused before creating another.
+        let c = pool.lease().unwrap();
+        assert_eq!(0, c.index());
+        let d = pool.lease().unwrap();
+        assert_eq!(2, d.index());
+        assert_eq!(3, pool.metrics().contexts);
+
+        // At the limit, leases share the least-loaded context.
+        let e = pool.lease().unwrap();
+        let f = pool.lease().unwrap();
+        assert_ne!(e.index(), f.index());
+        assert_eq!(vec![2, 2, 1], {
+            let mut loads = pool.loads();
+            loads.sort_unstable_by(|x, y| y.cmp(x));
+            loads
+        });
+
+        // Leases can be restricted to fewer contexts than the limit.
+        let g = pool.lease_up_to(1).unwrap();
+        assert_eq!(0, g.index());
+
+        drop((b, c, d, e, f, g));
+        let metrics = pool.metrics();
+        assert_eq!(7, metrics.leases);
+        assert_eq!(0, metrics.active_leases);
+        assert_eq!(vec![0, 0, 0], pool.loads());
+        assert_eq!(3, metrics.contexts);
+    }
+
+    #[test]
+    fn test_context_pool_wait_metrics() {
+        let pool = mock_pool(1);
+        let lease = pool.lease().unwrap();
+        let other = pool.lease().unwrap();
+        assert_eq!(lease.index(), other.index());
+
+        let guard = lease.lock();
+        let waiter = thread::spawn(move || {
+            let _ = other.lock();
+        });
+        thread::sleep(Duration::from_millis(50));
+        drop(guard);
+        waiter.join().unwrap();
+
+        let metrics = pool.metrics();
+        assert_eq!(2, metrics.locks);
+        assert!(metrics.max_wait >= Duration::from_millis(10));
+        assert!(metrics.total_wait >= metrics.max_wait);
+        assert_eq!(1, metrics.active_leases);
+    }
+
+    #[test]
+    fn test_context_pool_memory() {
+        let pool = mock_pool(1);
+        let mut a = pool.lease().unwrap();
+        let mut b = pool.lease().unwrap();
+        assert_eq!(a.index(), b.index());
+
+        // Leases of one context share its budget.
+        assert_eq!(60, a.reserve_memory(100, 60));
+        assert_eq!(40, b.reserve_memory(100, 60));
+        assert_eq!(0, b.reserve_memory(100, 10));
+        assert!(!b.try_reserve_memory(100, 10));
+        assert_eq!(vec![100], pool.reserved_memory());
+        assert_eq!((60, 40), (a.reserved_memory(), b.reserved_memory()));
+
+        // Reservations are returned on release, and when the lease is dropped.
+        a.release_memory();
+        assert_eq!(0, a.reserved_memory());
+        assert_eq!(vec![40], pool.reserved_memory());
+        assert!(!a.try_reserve_memory(100, 80));
+        assert!(a.try_reserve_memory(100, 60));
+        drop(b);
+        assert_eq!(vec![60], pool.reserved_memory());
+        drop(a);
+        assert_eq!(vec![0], pool.reserved_memory());
+    }
+
+    #[test]
+    fn test_context_pool_creation_failure() {
+        let pool =
+            ContextPool::<MockContext>::new(2, || Err(Error::GPUError("no device".to_string())));
+        assert!(pool.lease().is_err());
+        assert_eq!(PoolMetrics::default(), pool.metrics());
+    }
+}
@@ -298,6 +298,22 @@ where
     }
 
     pub fn hash_optimized_static(&mut self) -> E::Fr {
+        self.apply_permutation();
+
+        self.elements[1]
+    }
+
+    /// Apply the Poseidon permutation to `elements`, in place. Afterward, `elements` hold the canonical permuted
+    /// state, exactly as the unoptimized permutation would leave it, so the whole state (not only the hash
+    /// result) can be used to build other modes on the permutation.
+    pub fn permute(&mut self) {
+        self.constants_offset = 0;
+        self.current_round = 0;
+
+        self.apply_permutation();
+    }
+
+    fn apply_permutation(&mut self) {
         // The first full round should use the initial constants.
         self.add_round_constants();
 
@@ -322,8 +338,6 @@ where
             self.constants_offset,
             self.constants.compressed_round_constants.len()
         );
-
-        self.elements[1]
     }
 
     fn full_round(&mut self, last_round: bool) {
@@ -463,6 +477,22 @@ where
     }
 }
 
+/// Apply the Poseidon permutation for `constants` to `state`, in place. `state` must have `constants.width()`
+/// elements. See `Poseidon::permute`.
+pub fn permute<E, A>(constants: &PoseidonConstants<E, A>, state: &mut [E::Fr])
+where
+    E: ScalarEngine,
+    A: Arity<E::Fr>,
+{
+    assert_eq!(constants.width(), state.len(), "Invalid state size");
+
+    let mut p = Poseidon::new(constants);
+    p.elements.copy_from_slice(state);
+    p.permute();
+
+    state.copy_from_slice(&p.elements);
+}
+
 #[derive(Debug)]
 pub struct SimplePoseidonBatchHasher<'a, A>
 where
@@ -742,6 +772,50 @@ mod tests {
         assert_eq!(digest_correct, digest_optimized_static);
     }
 
+    #[test]
+    fn permute_is_canonical() {
+        permute_is_canonical_aux::<typenum::U2>();
+        permute_is_canonical_aux::<typenum::U4>();
+        permute_is_canonical_aux::<typenum::U8>();
+        permute_is_canonical_aux::<typenum::U11>();
+    }
+
+    fn permute_is_canonical_aux<A>()
+    where
+        A: Arity<Fr>,
+    {
+        let constants = PoseidonConstants::<Bls12, A>::new();
+        let state: Vec<Fr> = (0..constants.width())
+            .map(|n| scalar_from_u64::<Fr>(3 * n as u64 + 1))
+            .collect();
+
+        let mut expected = Poseidon::<Bls12, A>::new(&constants);
+        expected.elements.copy_from_slice(&state);
+        hash_correct(&mut expected);
+
+        let mut p = Poseidon::<Bls12, A>::new(&constants);
+        p.elements.copy_from_slice(&state);
+        p.permute();
+        assert_eq!(expected.elements, p.elements);
+
+        let mut free_state = state.clone();
+        permute(&constants, &mut free_state);
+        assert_eq!(&expected.elements[..], &free_state[..]);
+
+        // Permuting again applies the permutation to the permuted state.
+        expected.constants_offset = 0;
+        hash_correct(&mut expected);
+        p.permute();
+        assert_eq!(expected.elements, p.elements);
+
+        // Hashing is the permutation of the tagged preimage, with the result in the second element.
+        let mut h = Poseidon::<Bls12, A>::new_with_preimage(&state[1..], &constants);
+        let mut tagged = state.clone();
+        tagged[0] = constants.arity_tag;
+        permute(&constants, &mut tagged);
+        assert_eq!(h.hash(), tagged[1]);
+    }
+
     #[test]
     fn default_is_standard() {
         let default_constants = PoseidonConstants::<Bls12, U8>::new();
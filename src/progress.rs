@@ -0,0 +1,144 @@
+use crate::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The phase of a build which a `Progress` report describes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BuildPhase {
+    /// Hashing columns into the base row of a column tree.
+    Columns,
+    /// Hashing the rows of a tree above its base row.
+    Tree,
+}
+
+/// Snapshot of a build's progress, reported to a `ProgressObserver` after each batch.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Progress {
+    pub phase: BuildPhase,
+    /// Number of rows (excluding the base row) fully hashed, out of `total_rows`.
+    pub rows_done: usize,
+    pub total_rows: usize,
+    /// Number of hashes performed, out of `total_hashes`.
+    pub hashes_done: usize,
+    pub total_hashes: usize,
+    /// Time since the phase began.
+    pub elapsed: Duration,
+    /// Estimated time remaining, extrapolated from the hashing rate so far. `None` until some hashing is done.
+    pub eta: Option<Duration>,
+}
+
+/// `ProgressObserver`s receive `Progress` reports from tree builders. Any `Fn(&Progress)` can be used as one.
+pub trait ProgressObserver: Send + Sync {
+    fn on_progress(&self, progress: &Progress);
+}
+
+impl<F> ProgressObserver for F
+where
+    F: Fn(&Progress) + Send + Sync,
+{
+    fn on_progress(&self, progress: &Progress) {
+        self(progress)
+    }
+}
+
+/// `CancellationToken` requests cooperative cancellation of a build. Builders check it between batches and return
+/// `Error::Cancelled` once it has been cancelled. Clones share the same state, so one clone can be handed to a builder
+/// and another kept (or sent to another thread) to cancel it.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Tracks the progress of one build phase, reporting to its observer and checking its cancellation token.
+pub(crate) struct ProgressTracker {
+    observer: Option<Arc<dyn ProgressObserver>>,
+    cancellation: Option<CancellationToken>,
+    start: Instant,
+    progress: Progress,
+    /// Hashes restored rather than performed, which don't count towards the hashing rate.
+    restored_hashes: usize,
+}
+
+impl ProgressTracker {
+    pub(crate) fn new(
+        phase: BuildPhase,
+        observer: Option<Arc<dyn ProgressObserver>>,
+        cancellation: Option<CancellationToken>,
+        total_rows: usize,
+        total_hashes: usize,
+    ) -> Self {
+        Self {
+            observer,
+            cancellation,
+            start: Instant::now(),
+            progress: Progress {
+                phase,
+                rows_done: 0,
+                total_rows,
+                hashes_done: 0,
+                total_hashes,
+                elapsed: Duration::from_secs(0),
+                eta: None,
+            },
+            restored_hashes: 0,
+        }
+    }
+
+    /// Return `Error::Cancelled` if cancellation has been requested.
+    pub(crate) fn check_cancelled(&self) -> Result<(), Error> {
+        match &self.cancellation {
+            Some(token) if token.is_cancelled() => Err(Error::Cancelled),
+            _ => Ok(()),
+        }
+    }
+
+    /// Record that a batch of `hashes` was performed, completing a row if `completes_row`, and report progress.
+    pub(crate) fn batch_done(&mut self, hashes: usize, completes_row: bool) {
+        self.progress.hashes_done += hashes;
+        if completes_row {
+            self.progress.rows_done += 1;
+        }
+        self.report();
+    }
+
+    /// Record that a row of `hashes` was restored from a checkpoint, and report progress.
+    pub(crate) fn row_restored(&mut self, hashes: usize) {
+        self.restored_hashes += hashes;
+        self.batch_done(hashes, true);
+    }
+
+    fn report(&mut self) {
+        let elapsed = self.start.elapsed();
+        let done = self.progress.hashes_done - self.restored_hashes;
+        let remaining = self
+            .progress
+            .total_hashes
+            .saturating_sub(self.progress.hashes_done);
+
+        self.progress.elapsed = elapsed;
+        self.progress.eta = if done > 0 {
+            Some(Duration::from_secs_f64(
+                elapsed.as_secs_f64() * remaining as f64 / done as f64,
+            ))
+        } else {
+            None
+        };
+
+        if let Some(observer) = &self.observer {
+            observer.on_progress(&self.progress);
+        }
+    }
+}
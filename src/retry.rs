@@ -0,0 +1,267 @@
+use crate::batch_hasher::BatcherConfig;
+use crate::error::Error;
+use crate::{Arity, BatchHasher};
+use generic_array::GenericArray;
+use log::info;
+use paired::bls12_381::Fr;
+use std::marker::PhantomData;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Whether `error` may succeed if the operation which caused it is retried. Only device errors are transient: errors
+/// in the input (such as `DecodingError` or `ArityMismatch`) or in the caller's use of the library will recur.
+pub fn is_transient(error: &Error) -> bool {
+    match error {
+        Error::GPUError(_) => true,
+        _ => false,
+    }
+}
+
+/// `RetryPolicy` decides whether, and when, to retry a failed operation.
+pub trait RetryPolicy {
+    /// Return how long to wait before retrying, after `attempt` (counting from 1) failed with `error`, `elapsed` after
+    /// the first attempt started. Returns `None` to give up.
+    fn next_delay(&self, attempt: usize, elapsed: Duration, error: &Error) -> Option<Duration>;
+}
+
+/// Retry transient errors, waiting exponentially longer before each retry, until `max_attempts` attempts have been
+/// made, or retrying would pass the `deadline`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExponentialBackoff {
+    pub max_attempts: usize,
+    /// Time to wait before the first retry.
+    pub initial_delay: Duration,
+    /// Factor by which the delay grows with each retry.
+    pub multiplier: u32,
+    /// Upper bound on the time to wait before any retry.
+    pub max_delay: Duration,
+    /// Time after the first attempt after which no retry is started.
+    pub deadline: Option<Duration>,
+}
+
+impl ExponentialBackoff {
+    /// The retry policy configured by `config`.
+    pub fn from_config(config: &BatcherConfig) -> Self {
+        Self {
+            max_attempts: config.retry_count,
+            initial_delay: config.retry_backoff,
+            multiplier: 2,
+            max_delay: Duration::from_secs(60),
+            deadline: config.retry_deadline,
+        }
+    }
+
+    /// A policy which never retries.
+    pub fn never() -> Self {
+        Self {
+            max_attempts: 1,
+            initial_delay: Duration::from_secs(0),
+            multiplier: 1,
+            max_delay: Duration::from_secs(0),
+            deadline: None,
+        }
+    }
+
+    /// The delay before the retry following `attempt`.
+    fn delay(&self, attempt: usize) -> Duration {
+        let mut delay = self.initial_delay;
+        for _ in 1..attempt {
+            delay = match delay.checked_mul(self.multiplier) {
+                Some(d) if d < self.max_delay => d,
+                _ => return self.max_delay,
+            };
+        }
+        Duration::min(delay, self.max_delay)
+    }
+}
+
+impl RetryPolicy for ExponentialBackoff {
+    fn next_delay(&self, attempt: usize, elapsed: Duration, error: &Error) -> Option<Duration> {
+        if !is_transient(error) || attempt >= self.max_attempts {
+            return None;
+        }
+
+        let delay = self.delay(attempt);
+        match self.deadline {
+            Some(deadline) if elapsed + delay > deadline => None,
+            _ => Some(delay),
+        }
+    }
+}
+
+/// Run `op` until it succeeds, or `policy` gives up, returning the last error.
+pub fn retry<T, P, F>(policy: &P, mut op: F) -> Result<T, Error>
+where
+    P: RetryPolicy + ?Sized,
+    F: FnMut() -> Result<T, Error>,
+{
+    let start = Instant::now();
+    let mut attempt = 1;
+    loop {
+        let e = match op() {
+            Ok(res) => return Ok(res),
+            Err(e) => e,
+        };
+
+        match policy.next_delay(attempt, start.elapsed(), &e) {
+            Some(delay) => {
+                info!("Attempt {} failed: {}. Retrying in {:?}", attempt, e, delay);
+                thread::sleep(delay);
+                attempt += 1;
+            }
+            None => {
+                info!("Attempt {} failed: {}. Giving up", attempt, e);
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// `RetryingBatchHasher` retries the batches of any `BatchHasher` which fail, according to a `RetryPolicy`.
+pub struct RetryingBatchHasher<H, P, A>
+where
+    H: BatchHasher<A>,
+    P: RetryPolicy,
+    A: Arity<Fr>,
+{
+    inner: H,
+    policy: P,
+    _a: PhantomData<A>,
+}
+
+impl<H, P, A> RetryingBatchHasher<H, P, A>
+where
+    H: BatchHasher<A>,
+    P: RetryPolicy,
+    A: Arity<Fr>,
+{
+    pub fn new(inner: H, policy: P) -> Self {
+        Self {
+            inner,
+            policy,
+            _a: PhantomData::<A>,
+        }
+    }
+
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+}
+
+impl<H, P, A> BatchHasher<A> for RetryingBatchHasher<H, P, A>
+where
+    H: BatchHasher<A>,
+    P: RetryPolicy,
+    A: Arity<Fr>,
+{
+    fn hash(&mut self, preimages: &[GenericArray<Fr, A>]) -> Result<Vec<Fr>, Error> {
+        let inner = &mut self.inner;
+        retry(&self.policy, || inner.hash(preimages))
+    }
+
+    fn max_batch_size(&self) -> usize {
+        self.inner.max_batch_size()
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        self.inner.sync()
+    }
+
+    fn release(&mut self) -> Result<(), Error> {
+        self.inner.release()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_device::{fake_hasher, FakeDeviceControl, FakeOperation};
+    use ff::Field;
+    use generic_array::sequence::GenericSequence;
+    use generic_array::typenum::U2;
+
+    fn policy() -> ExponentialBackoff {
+        ExponentialBackoff {
+            max_attempts: 5,
+            initial_delay: Duration::from_millis(1),
+            multiplier: 2,
+            max_delay: Duration::from_millis(5),
+            deadline: None,
+        }
+    }
+
+    #[test]
+    fn test_exponential_backoff() {
+        let policy = policy();
+        let transient = Error::GPUError("busy".to_string());
+        let zero = Duration::from_secs(0);
+
+        let delays: Vec<Option<Duration>> = (1..=5)
+            .map(|attempt| policy.next_delay(attempt, zero, &transient))
+            .collect();
+        let ms = |n| Some(Duration::from_millis(n));
+        assert_eq!(vec![ms(1), ms(2), ms(4), ms(5), None], delays);
+
+        // Fatal errors are never retried.
+        for fatal in &[
+            Error::DecodingError,
+            Error::ArityMismatch {
+                expected: 2,
+                actual: 8,
+            },
+            Error::Other("bug".to_string()),
+        ] {
+            assert!(!is_transient(fatal));
+            assert_eq!(None, policy.next_delay(1, zero, fatal));
+        }
+
+        // Nor is anything past the deadline.
+        let policy = ExponentialBackoff {
+            deadline: Some(Duration::from_millis(10)),
+            ..policy
+        };
+        assert_eq!(ms(1), policy.next_delay(1, zero, &transient));
+        assert_eq!(
+            None,
+            policy.next_delay(2, Duration::from_millis(9), &transient)
+        );
+
+        assert_eq!(
+            None,
+            ExponentialBackoff::never().next_delay(1, zero, &transient)
+        );
+    }
+
+    #[test]
+    fn test_retrying_batch_hasher() {
+        let control = FakeDeviceControl::new();
+        let mut device = fake_hasher::<U2>(&control, &BatcherConfig::new(16)).unwrap();
+        device.set_retry_policy(ExponentialBackoff::never());
+        let mut hasher = RetryingBatchHasher::new(device, policy());
+        let preimages = vec![GenericArray::<Fr, U2>::generate(|_| Fr::zero()); 4];
+
+        control.fail_next(
+            FakeOperation::Submit,
+            4,
+            Error::GPUError("busy".to_string()),
+        );
+        assert_eq!(4, hasher.hash(&preimages).unwrap().len());
+        assert_eq!(5, control.calls(FakeOperation::Submit));
+
+        control.fail_next(
+            FakeOperation::Submit,
+            5,
+            Error::GPUError("busy".to_string()),
+        );
+        assert!(hasher.hash(&preimages).is_err());
+        assert_eq!(10, control.calls(FakeOperation::Submit));
+
+        // A fatal error fails the batch immediately.
+        control.fail_next(FakeOperation::Submit, 1, Error::DecodingError);
+        match hasher.hash(&preimages) {
+            Err(Error::DecodingError) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(11, control.calls(FakeOperation::Submit));
+    }
+}
@@ -0,0 +1,398 @@
+use crate::batch_hasher::{Batcher, BatcherConfig, BatcherType};
+use crate::error::Error;
+use crate::{Arity, BatchHasher};
+use generic_array::GenericArray;
+use paired::bls12_381::Fr;
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Weight of the latest measurement in a worker's throughput estimate.
+const THROUGHPUT_SMOOTHING: f64 = 0.5;
+
+/// `SplitBatchHasher` hashes each batch on several batch hashers (such as GPU contexts and CPU batchers) at once,
+/// each on its own worker thread. A batch is split into contiguous parts proportional to each worker's measured
+/// throughput, so all parts take about as long, and the results are merged in order.
+///
+/// Until a worker has hashed a part, it is assumed to be as fast as the others.
+pub struct SplitBatchHasher<A>
+where
+    A: Arity<Fr>,
+{
+    workers: Vec<Worker<A>>,
+}
+
+struct Worker<A>
+where
+    A: Arity<Fr>,
+{
+    sender: Option<Sender<Vec<GenericArray<Fr, A>>>>,
+    receiver: Receiver<(Result<Vec<Fr>, Error>, Duration)>,
+    handle: Option<JoinHandle<()>>,
+    max_batch_size: usize,
+    /// Estimated preimages hashed per second, or `None` before the first measurement.
+    throughput: Option<f64>,
+}
+
+impl<A> SplitBatchHasher<A>
+where
+    A: 'static + Arity<Fr>,
+    GenericArray<Fr, A>: Send,
+{
+    pub fn new() -> Self {
+        Self {
+            workers: Vec::new(),
+        }
+    }
+
+    /// Create a hasher splitting batches across batchers of each of `types`, configured by `config`.
+    pub fn from_batchers(types: &[BatcherType], config: &BatcherConfig) -> Result<Self, Error> {
+        let mut hasher = Self::new();
+        for t in types {
+            let (t, config) = (*t, config.clone());
+            hasher.add_worker(move || Batcher::<A>::new(&t, &config))?;
+        }
+        Ok(hasher)
+    }
+
+    /// Add a worker hashing with the batch hasher created by `factory`. The hasher is created on the worker's thread,
+    /// so need not be `Send`. Returns the error of `factory`, if it fails.
+    pub fn add_worker<F, H>(&mut self, factory: F) -> Result<(), Error>
+    where
+        F: 'static + FnOnce() -> Result<H, Error> + Send,
+        H: BatchHasher<A>,
+    {
+        let (sender, batches) = channel::<Vec<GenericArray<Fr, A>>>();
+        let (results, receiver) = channel();
+        let (ready_sender, ready_receiver) = sync_channel(1);
+
+        let handle = thread::spawn(move || run_worker(factory, ready_sender, batches, results));
+
+        let max_batch_size = ready_receiver
+            .recv()
+            .map_err(|_| Error::Other("split worker exited before initializing".to_string()))??;
+
+        self.workers.push(Worker {
+            sender: Some(sender),
+            receiver,
+            handle: Some(handle),
+            max_batch_size,
+            throughput: None,
+        });
+        Ok(())
+    }
+
+    pub fn worker_count(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Estimated preimages hashed per second by each worker, or `None` for workers which have not hashed yet.
+    pub fn throughputs(&self) -> Vec<Option<f64>> {
+        self.workers.iter().map(|w| w.throughput).collect()
+    }
+
+    /// Number of preimages of a batch of `count` to be hashed by each worker.
+    fn split(&self, count: usize) -> Vec<usize> {
+        let measured: Vec<f64> = self.workers.iter().filter_map(|w| w.throughput).collect();
+        let default = if measured.is_empty() {
+            1.0
+        } else {
+            measured.iter().sum::<f64>() / measured.len() as f64
+        };
+
+        let throughputs: Vec<f64> = self
+            .workers
+            .iter()
+            .map(|w| w.throughput.unwrap_or(default))
+            .collect();
+        let limits: Vec<usize> = self.workers.iter().map(|w| w.max_batch_size).collect();
+
+        split_sizes(count, &throughputs, &limits)
+    }
+}
+
+impl<A> BatchHasher<A> for SplitBatchHasher<A>
+where
+    A: 'static + Arity<Fr>,
+    GenericArray<Fr, A>: Send,
+{
+    fn hash(&mut self, preimages: &[GenericArray<Fr, A>]) -> Result<Vec<Fr>, Error> {
+        if self.workers.is_empty() {
+            return Err(Error::Other("SplitBatchHasher has no workers".to_string()));
+        }
+
+        let sizes = self.split(preimages.len());
+
+        let mut start = 0;
+        for (worker, size) in self.workers.iter().zip(sizes.iter()) {
+            if *size > 0 {
+                let part = preimages[start..start + size].to_vec();
+                worker
+                    .sender
+                    .as_ref()
+                    .expect("sender is only taken on drop")
+                    .send(part)
+                    .map_err(|_| Error::Other("split worker exited".to_string()))?;
+            }
+            start += size;
+        }
+
+        // Collect every part, even after a failure, so no result is left to be mistaken for a later batch's.
+        let mut hashes = Vec::with_capacity(preimages.len());
+        let mut first_error = None;
+        for (worker, size) in self.workers.iter_mut().zip(sizes.iter()) {
+            if *size == 0 {
+                continue;
+            }
+
+            match worker.receiver.recv() {
+                Ok((Ok(part), elapsed)) => {
+                    worker.record(*size, elapsed);
+                    hashes.extend(part);
+                }
+                Ok((Err(e), _)) => {
+                    first_error = first_error.or(Some(e));
+                }
+                Err(_) => {
+                    first_error = first_error
+                        .or_else(|| Some(Error::Other("split worker exited".to_string())));
+                }
+            }
+        }
+
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(hashes),
+        }
+    }
+
+    fn max_batch_size(&self) -> usize {
+        self.workers.iter().map(|w| w.max_batch_size).sum()
+    }
+
+    /// Stop every worker, releasing its hasher. Returns the first error, after stopping them all.
+    fn release(&mut self) -> Result<(), Error> {
+        let mut first_error = None;
+        for mut worker in self.workers.drain(..) {
+            if let Err(e) = worker.stop() {
+                first_error = first_error.or(Some(e));
+            }
+        }
+
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<A> Worker<A>
+where
+    A: Arity<Fr>,
+{
+    /// Stop the worker, which releases its hasher, returning the result of the release.
+    fn stop(&mut self) -> Result<(), Error> {
+        // Closing the channel stops the worker, which then sends the result of releasing its hasher.
+        if self.sender.take().is_none() {
+            return Ok(());
+        }
+        let released = match self.receiver.recv() {
+            Ok((res, _)) => res.map(|_| ()),
+            Err(_) => Ok(()),
+        };
+
+        if let Some(handle) = self.handle.take() {
+            handle
+                .join()
+                .map_err(|_| Error::Other("split worker panicked".to_string()))?;
+        }
+        released
+    }
+
+    /// Update the throughput estimate with a part of `count` preimages hashed in `elapsed`.
+    fn record(&mut self, count: usize, elapsed: Duration) {
+        let seconds = f64::max(elapsed.as_secs_f64(), 1e-6);
+        let measured = count as f64 / seconds;
+
+        self.throughput = Some(match self.throughput {
+            Some(t) => t + THROUGHPUT_SMOOTHING * (measured - t),
+            None => measured,
+        });
+    }
+}
+
+impl<A> Drop for Worker<A>
+where
+    A: Arity<Fr>,
+{
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+fn run_worker<A, F, H>(
+    factory: F,
+    ready: SyncSender<Result<usize, Error>>,
+    batches: Receiver<Vec<GenericArray<Fr, A>>>,
+    results: Sender<(Result<Vec<Fr>, Error>, Duration)>,
+) where
+    A: Arity<Fr>,
+    F: FnOnce() -> Result<H, Error>,
+    H: BatchHasher<A>,
+{
+    let mut hasher = match factory() {
+        Ok(hasher) => {
+            let _ = ready.send(Ok(hasher.max_batch_size()));
+            hasher
+        }
+        Err(e) => {
+            let _ = ready.send(Err(e));
+            return;
+        }
+    };
+
+    for batch in batches {
+        let start = Instant::now();
+        let res = hasher.hash(&batch);
+        if results.send((res, start.elapsed())).is_err() {
+            // The hasher is released below even though no one is waiting for the result.
+            break;
+        }
+    }
+
+    let start = Instant::now();
+    let released = hasher.release().map(|_| Vec::new());
+    let _ = results.send((released, start.elapsed()));
+}
+
+/// Split `count` preimages among workers proportionally to their `throughputs`, giving no worker more than its limit
+/// unless the limits add up to less than `count`, in which case the fastest worker takes the excess.
+fn split_sizes(count: usize, throughputs: &[f64], limits: &[usize]) -> Vec<usize> {
+    assert_eq!(throughputs.len(), limits.len());
+    if throughputs.is_empty() {
+        return Vec::new();
+    }
+
+    let total: f64 = throughputs.iter().sum();
+    let mut sizes: Vec<usize> = throughputs
+        .iter()
+        .zip(limits.iter())
+        .map(|(t, limit)| usize::min((count as f64 * t / total).floor() as usize, *limit))
+        .collect();
+
+    // Hand out what rounding and the limits left over, fastest first.
+    let mut fastest_first: Vec<usize> = (0..throughputs.len()).collect();
+    fastest_first.sort_by(|a, b| throughputs[*b].partial_cmp(&throughputs[*a]).unwrap());
+
+    let mut remaining = count - sizes.iter().sum::<usize>();
+    for i in fastest_first.iter() {
+        let extra = usize::min(remaining, limits[*i].saturating_sub(sizes[*i]));
+        sizes[*i] += extra;
+        remaining -= extra;
+    }
+    sizes[fastest_first[0]] += remaining;
+
+    sizes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_device::{
+        fake_hasher, random_preimages_and_hashes, FakeDeviceControl, FakeOperation,
+    };
+    use crate::poseidon::{Poseidon, PoseidonConstants, SimplePoseidonBatchHasher};
+    use ff::Field;
+    use generic_array::sequence::GenericSequence;
+    use generic_array::typenum::U8;
+    use paired::bls12_381::Bls12;
+
+    #[test]
+    fn test_split_sizes() {
+        assert_eq!(vec![50, 50], split_sizes(100, &[1.0, 1.0], &[100, 100]));
+        assert_eq!(vec![25, 75], split_sizes(100, &[1.0, 3.0], &[100, 100]));
+        // Rounding leftovers go to the fastest worker.
+        assert_eq!(
+            vec![3, 4, 3],
+            split_sizes(10, &[1.0, 1.1, 1.0], &[10, 10, 10])
+        );
+        // Limits are respected while possible.
+        assert_eq!(vec![40, 60], split_sizes(100, &[1.0, 9.0], &[100, 60]));
+        // Beyond the limits, the fastest worker takes the excess.
+        assert_eq!(vec![5, 25], split_sizes(30, &[1.0, 2.0], &[5, 5]));
+        assert_eq!(vec![0, 0], split_sizes(0, &[1.0, 2.0], &[5, 5]));
+    }
+
+    #[test]
+    fn test_split_batch_hasher() {
+        let (preimages, expected) = random_preimages_and_hashes::<U8>(100);
+
+        let config = BatcherConfig {
+            retry_count: 1,
+            ..BatcherConfig::new(64)
+        };
+        let slow = FakeDeviceControl::new();
+        slow.set_latency(Duration::from_millis(50));
+
+        let mut hasher = SplitBatchHasher::<U8>::new();
+        let slow_control = slow.clone();
+        hasher
+            .add_worker(move || fake_hasher::<U8>(&slow_control, &config))
+            .unwrap();
+        hasher
+            .add_worker(|| SimplePoseidonBatchHasher::<U8>::new(64))
+            .unwrap();
+        assert_eq!(128, hasher.max_batch_size());
+
+        // A failed part fails the batch, without disturbing later batches.
+        slow.fail_next(
+            FakeOperation::Submit,
+            1,
+            Error::GPUError("injected".to_string()),
+        );
+        assert!(hasher.hash(&preimages).is_err());
+
+        // Until the slow device is measured, the batch is split evenly.
+        assert_eq!(expected, hasher.hash(&preimages).unwrap());
+        assert_eq!(50, slow.preimages_hashed());
+
+        // Afterwards, the slow device gets a smaller share.
+        let throughputs = hasher.throughputs();
+        assert!(throughputs[0].unwrap() < throughputs[1].unwrap());
+        assert_eq!(expected, hasher.hash(&preimages).unwrap());
+        assert!(slow.preimages_hashed() < 100);
+
+        // Releasing stops the workers, releasing their hashers.
+        hasher.release().unwrap();
+        assert_eq!(0, hasher.worker_count());
+        assert_eq!(1, slow.calls(FakeOperation::Release));
+    }
+
+    #[test]
+    fn test_split_batch_hasher_from_batchers() {
+        let types = [BatcherType::CPU, BatcherType::CPU];
+        let mut hasher =
+            SplitBatchHasher::<U8>::from_batchers(&types, &BatcherConfig::new(32)).unwrap();
+        assert_eq!(2, hasher.worker_count());
+
+        // Custom batchers need a backend factory, which `from_batchers` cannot supply.
+        let types = [BatcherType::CPU, BatcherType::Custom];
+        match SplitBatchHasher::<U8>::from_batchers(&types, &BatcherConfig::new(32)) {
+            Err(Error::InvalidConfiguration(_)) => (),
+            _ => panic!("expected an invalid configuration"),
+        }
+
+        let preimages = vec![GenericArray::<Fr, U8>::generate(|_| Fr::one()); 10];
+        let constants = PoseidonConstants::<Bls12, U8>::new();
+        let expected = Poseidon::new_with_preimage(&preimages[0], &constants).hash();
+        assert_eq!(vec![expected; 10], hasher.hash(&preimages).unwrap());
+
+        // Factory failures are returned from `add_worker`.
+        assert!(hasher
+            .add_worker(|| -> Result<SimplePoseidonBatchHasher<U8>, Error> {
+                Err(Error::GPUError("no device".to_string()))
+            })
+            .is_err());
+        assert_eq!(2, hasher.worker_count());
+    }
+}
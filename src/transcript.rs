@@ -0,0 +1,139 @@
+//! Fiat–Shamir transcripts, built on a Poseidon duplex sponge.
+//!
+//! Field elements are absorbed into the rate (every element of the state but the first), permuting whenever it is
+//! full. Squeezing a challenge always permutes first, so every absorbed element affects it, and returns the second
+//! element of the state. The first element is initialized with a domain tag (`2^65`), which separates transcripts
+//! from hashing and encryption, and is never absorbed into.
+//!
+//! Labels are mapped to field elements by `label_to_fr`, and absorbed before the message or challenge they label.
+//! Messages are also prefixed with their length, so that the boundaries between messages are unambiguous.
+//!
+//! `circuit::TranscriptCircuit` performs the same operations in a circuit, producing identical challenges.
+use crate::poseidon::{Arity, Poseidon, PoseidonConstants};
+use crate::scalar_from_u64;
+use blake2s_simd::Params;
+use ff::{Field, PrimeField, PrimeFieldRepr, ScalarEngine};
+
+/// The first element of the initial transcript state.
+pub(crate) fn domain_tag<E: ScalarEngine>() -> E::Fr {
+    let mut tag = scalar_from_u64::<E::Fr>(1 << 32);
+    tag.square();
+    tag.double();
+    tag
+}
+
+/// Map `label` to a field element: the first 248 bits of its (personalized) BLAKE2s digest, read little-endian.
+pub fn label_to_fr<E: ScalarEngine>(label: &[u8]) -> E::Fr {
+    let digest = Params::new().personal(b"nptnlabl").hash(label);
+
+    let mut bytes = [0u8; 32];
+    bytes[..31].copy_from_slice(&digest.as_bytes()[..31]);
+
+    let mut repr = <E::Fr as PrimeField>::Repr::default();
+    repr.read_le(&bytes[..]).expect("failed to read label repr");
+
+    E::Fr::from_repr(repr).expect("248-bit label must be a valid field element")
+}
+
+/// A Fiat–Shamir transcript.
+pub struct Transcript<'a, E, A>
+where
+    E: ScalarEngine,
+    A: Arity<E::Fr>,
+{
+    state: Poseidon<'a, E, A>,
+    /// Number of elements absorbed into the rate since the last permutation.
+    absorbed: usize,
+}
+
+impl<'a, E, A> Transcript<'a, E, A>
+where
+    E: ScalarEngine,
+    A: Arity<E::Fr>,
+{
+    /// Create a transcript for the protocol identified by `label`.
+    pub fn new(constants: &'a PoseidonConstants<E, A>, label: &[u8]) -> Self {
+        let mut state = Poseidon::new(constants);
+        state.elements[0] = domain_tag::<E>();
+
+        let mut transcript = Self { state, absorbed: 0 };
+        transcript.append(label_to_fr::<E>(label));
+        transcript
+    }
+
+    /// Absorb `element`.
+    pub fn append(&mut self, element: E::Fr) {
+        if self.absorbed == self.state.constants.arity() {
+            self.state.permute();
+            self.absorbed = 0;
+        }
+
+        self.state.elements[self.absorbed + 1].add_assign(&element);
+        self.absorbed += 1;
+    }
+
+    /// Absorb `message`, with its `label` and length.
+    pub fn append_message(&mut self, label: &[u8], message: &[E::Fr]) {
+        self.append(label_to_fr::<E>(label));
+        self.append(scalar_from_u64::<E::Fr>(message.len() as u64));
+        for element in message {
+            self.append(*element);
+        }
+    }
+
+    /// Absorb `label`, then squeeze a challenge.
+    pub fn challenge(&mut self, label: &[u8]) -> E::Fr {
+        self.append(label_to_fr::<E>(label));
+
+        self.state.permute();
+        self.absorbed = 0;
+
+        self.state.elements[1]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use generic_array::typenum::U4;
+    use paired::bls12_381::{Bls12, Fr};
+
+    fn challenge_after(messages: &[(&str, Vec<Fr>)]) -> Fr {
+        let constants = PoseidonConstants::<Bls12, U4>::new();
+        let mut transcript = Transcript::new(&constants, b"test protocol");
+        for (label, message) in messages {
+            transcript.append_message(label.as_bytes(), message);
+        }
+        transcript.challenge(b"challenge")
+    }
+
+    #[test]
+    fn test_transcript() {
+        let fr = scalar_from_u64::<Fr>;
+        let message = vec![fr(1), fr(2), fr(3), fr(4), fr(5)];
+
+        let challenge = challenge_after(&[("a", message.clone())]);
+        assert_eq!(challenge, challenge_after(&[("a", message.clone())]));
+
+        // Challenges depend on labels, contents and message boundaries.
+        assert_ne!(challenge, challenge_after(&[("b", message.clone())]));
+        assert_ne!(challenge, challenge_after(&[("a", message[1..].to_vec())]));
+        assert_ne!(
+            challenge,
+            challenge_after(&[("a", message[..2].to_vec()), ("a", message[2..].to_vec())])
+        );
+
+        // Successive challenges differ.
+        let constants = PoseidonConstants::<Bls12, U4>::new();
+        let mut transcript = Transcript::new(&constants, b"test protocol");
+        let first = transcript.challenge(b"challenge");
+        let second = transcript.challenge(b"challenge");
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn test_label_to_fr() {
+        assert_eq!(label_to_fr::<Bls12>(b"a"), label_to_fr::<Bls12>(b"a"));
+        assert_ne!(label_to_fr::<Bls12>(b"a"), label_to_fr::<Bls12>(b"b"));
+    }
+}
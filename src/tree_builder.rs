@@ -1,10 +1,40 @@
-use crate::batch_hasher::{Batcher, BatcherType};
+use crate::backend::BackendFactory;
+use crate::batch_hasher::{Batcher, BatcherConfig, BatcherType};
+use crate::checkpoint::{Checkpoint, TreeId};
 use crate::error::Error;
 use crate::poseidon::{Poseidon, PoseidonConstants};
-use crate::{Arity, BatchHasher};
+use crate::progress::{BuildPhase, CancellationToken, ProgressObserver, ProgressTracker};
+use crate::{Arity, BatchHasher, DEFAULT_STRENGTH};
 use ff::Field;
 use generic_array::GenericArray;
+use log::{info, warn};
 use paired::bls12_381::{Bls12, Fr};
+use std::sync::Arc;
+
+/// Minimum number of preimages for which `hash_row` uses the tree batcher, rather than hashing each preimage directly.
+const MIN_BATCHED_ROW_SIZE: usize = 64;
+
+/// How `build_tree` hashes the rows above the leaves.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TreeBuildStrategy {
+    /// All rows in one call to the batcher's whole-tree entry point.
+    WholeTree,
+    /// One row at a time, in batches.
+    RowByRow,
+}
+
+/// Choose how to build a tree of `leaf_count` leaves, of which `rows_restored` rows were restored from a checkpoint,
+/// with a batcher which can build trees of `whole_tree_leaves` leaves in one call, if any.
+pub fn select_build_strategy(
+    whole_tree_leaves: Option<usize>,
+    leaf_count: usize,
+    rows_restored: usize,
+) -> TreeBuildStrategy {
+    match whole_tree_leaves {
+        Some(leaves) if leaves == leaf_count && rows_restored == 0 => TreeBuildStrategy::WholeTree,
+        _ => TreeBuildStrategy::RowByRow,
+    }
+}
 
 pub trait TreeBuilderTrait<TreeArity>
 where
@@ -27,8 +57,12 @@ where
     tree_constants: PoseidonConstants<Bls12, TreeArity>,
     tree_batcher: Option<Batcher<'a, TreeArity>>,
     rows_to_discard: usize,
-    max_tree_batch_size: usize,
+    config: BatcherConfig,
     t: Option<BatcherType>,
+    backend: Option<BackendFactory<TreeArity>>,
+    observer: Option<Arc<dyn ProgressObserver>>,
+    cancellation: Option<CancellationToken>,
+    checkpoint: Option<Checkpoint>,
 }
 
 impl<TreeArity> TreeBuilderTrait<TreeArity> for TreeBuilder<'_, TreeArity>
@@ -83,16 +117,46 @@ fn as_generic_arrays<'a, A: Arity<Fr>>(vec: &'a [Fr]) -> &'a [GenericArray<Fr, A
     }
 }
 
-impl<TreeArity> TreeBuilder<'_, TreeArity>
+impl<'a, TreeArity> TreeBuilder<'a, TreeArity>
 where
     TreeArity: Arity<Fr>,
 {
+    /// Create a builder for a tree of `leaf_count` leaves, hashed by batchers of type `t` configured by `config`.
     pub fn new(
         t: Option<BatcherType>,
         leaf_count: usize,
-        max_tree_batch_size: usize,
+        config: BatcherConfig,
+        rows_to_discard: usize,
+    ) -> Result<Self, Error> {
+        Self::create(t, None, leaf_count, config, rows_to_discard)
+    }
+
+    /// Create a builder for a tree of `leaf_count` leaves, hashed by `BatcherType::Custom` batchers on the backends
+    /// created by `backend`, configured by `config`.
+    pub fn with_backend(
+        backend: BackendFactory<TreeArity>,
+        leaf_count: usize,
+        config: BatcherConfig,
+        rows_to_discard: usize,
+    ) -> Result<Self, Error> {
+        Self::create(
+            Some(BatcherType::Custom),
+            Some(backend),
+            leaf_count,
+            config,
+            rows_to_discard,
+        )
+    }
+
+    pub(crate) fn create(
+        t: Option<BatcherType>,
+        backend: Option<BackendFactory<TreeArity>>,
+        leaf_count: usize,
+        config: BatcherConfig,
         rows_to_discard: usize,
     ) -> Result<Self, Error> {
+        config.validate()?;
+
         let builder = Self {
             leaf_count,
             data: vec![Fr::zero(); leaf_count],
@@ -100,8 +164,12 @@ where
             tree_constants: PoseidonConstants::<Bls12, TreeArity>::new(),
             tree_batcher: None,
             rows_to_discard: rows_to_discard,
-            max_tree_batch_size: max_tree_batch_size,
+            config,
             t: t,
+            backend,
+            observer: None,
+            cancellation: None,
+            checkpoint: None,
         };
 
         // Cannot discard the base row or the root.
@@ -114,7 +182,62 @@ where
         Ok(builder)
     }
 
+    /// Report progress of subsequent builds to `observer`, after each batch.
+    pub fn set_progress_observer(&mut self, observer: Arc<dyn ProgressObserver>) {
+        self.observer = Some(observer);
+    }
+
+    /// Check `token` between batches of subsequent builds, which return `Error::Cancelled` once it is cancelled.
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancellation = Some(token);
+    }
+
+    /// Persist each row of subsequent builds to `checkpoint` once it is complete. A build resumes from the rows
+    /// already checkpointed for the same leaves, and clears the checkpoint once the tree is complete. A checkpoint
+    /// which fails verification, or belongs to other leaves, fails the build with `Error::InvalidCheckpoint`.
+    pub fn set_checkpoint(&mut self, checkpoint: Checkpoint) {
+        self.checkpoint = Some(checkpoint);
+    }
+
+    /// Release the tree batcher, if there is one. Builds and updates release it before returning, whether or not
+    /// they succeed.
+    pub fn release(&mut self) -> Result<(), Error> {
+        match self.tree_batcher.take() {
+            Some(mut batcher) => batcher.release(),
+            None => Ok(()),
+        }
+    }
+
+    /// Release the tree batcher once `result` is computed. A release error is returned if `result` is `Ok`, and
+    /// logged otherwise, so the error which failed the operation is the one returned.
+    fn release_after<T>(&mut self, result: Result<T, Error>) -> Result<T, Error> {
+        let released = self.release();
+        match result {
+            Ok(value) => released.map(|_| value),
+            Err(e) => {
+                if let Err(release_error) = released {
+                    warn!("Failed to release tree batcher: {}", release_error);
+                }
+                Err(e)
+            }
+        }
+    }
+
     pub fn build_tree(&mut self, rows_to_discard: usize) -> Result<(Vec<Fr>, Vec<Fr>), Error> {
+        let built = self.hash_tree(rows_to_discard);
+        let (base_row, tree_to_keep) = self.release_after(built)?;
+
+        // The tree is complete, so its checkpoint is no longer needed.
+        if let Some(checkpoint) = &self.checkpoint {
+            checkpoint.clear()?;
+        }
+
+        Ok((base_row, tree_to_keep))
+    }
+
+    /// Hash every row of the tree, returning the base row and the rows to keep. The tree batcher is left for
+    /// `build_tree` to release.
+    fn hash_tree(&mut self, rows_to_discard: usize) -> Result<(Vec<Fr>, Vec<Fr>), Error> {
         let final_tree_size = self.tree_size(rows_to_discard);
         let intermediate_tree_size = self.tree_size(0) + self.leaf_count;
         let arity = TreeArity::to_usize();
@@ -123,28 +246,97 @@ where
 
         tree_data[0..self.leaf_count].copy_from_slice(&self.data);
 
-        let (mut start, mut end) = (0, arity);
+        let mut tracker = ProgressTracker::new(
+            BuildPhase::Tree,
+            self.observer.clone(),
+            self.cancellation.clone(),
+            self.tree_height(),
+            self.tree_size(0),
+        );
 
+        let tree_id = self
+            .checkpoint
+            .as_ref()
+            .map(|_| TreeId::new(&self.data, arity));
 
-        self.tree_batcher = if let Some(t) = &self.t {
-            Some(Batcher::<TreeArity>::new(t, self.max_tree_batch_size)?)
-        } else {
-            None
-        };
-        match &mut self.tree_batcher {
-            Some(batcher) => {
-                let max_batch_size = batcher.max_batch_size();
+        let (mut row_start, mut row_end) = (0, self.leaf_count);
+        let mut row = 0;
+
+        // Resume from the rows already checkpointed, if any.
+        if let (Some(checkpoint), Some(id)) = (&self.checkpoint, &tree_id) {
+            while row_end < intermediate_tree_size {
+                let new_row_size = (row_end - row_start) / arity;
+                match checkpoint.load_row(id, row, new_row_size)? {
+                    Some(data) => {
+                        tree_data[row_end..row_end + new_row_size].copy_from_slice(&data);
+                        tracker.row_restored(new_row_size);
+                    }
+                    None => break,
+                }
+                row += 1;
+                row_start = row_end;
+                row_end += new_row_size;
+            }
+            if row > 0 {
+                info!(
+                    "TreeBuilder.build_tree: resumed {} rows from checkpoint",
+                    row
+                );
+            }
+        }
+
+        self.release()?;
+        self.tree_batcher = self.new_batcher()?;
+
+        let whole_tree_leaves = self
+            .tree_batcher
+            .as_ref()
+            .and_then(|batcher| batcher.whole_tree_leaves());
+        // A whole-tree build saves no checkpoint rows: it only runs when no rows were restored, and the tree is
+        // complete, and its checkpoint cleared, as soon as the call returns.
+        if select_build_strategy(whole_tree_leaves, self.leaf_count, row)
+            == TreeBuildStrategy::WholeTree
+        {
+            tracker.check_cancelled()?;
 
-                let (mut row_start, mut row_end) = (0, self.leaf_count);
-                while row_end < intermediate_tree_size {
-                    let row_size = row_end - row_start;
-                    assert_eq!(0, row_size % arity);
-                    let new_row_size = row_size / arity;
-                    let (new_row_start, new_row_end) = (row_end, row_end + new_row_size);
+            let batcher = self
+                .tree_batcher
+                .as_mut()
+                .expect("whole-tree builds need a batcher");
+            let rows = batcher.build_whole_tree(&tree_data[..self.leaf_count])?;
+            if rows.len() != intermediate_tree_size - self.leaf_count {
+                return Err(Error::GPUError(format!(
+                    "whole-tree build returned {} nodes, but the tree has {}",
+                    rows.len(),
+                    intermediate_tree_size - self.leaf_count
+                )));
+            }
+            tree_data[self.leaf_count..].copy_from_slice(&rows);
+
+            // Report the rows as if they were built one at a time, leaving none for the loop below.
+            while row_end < intermediate_tree_size {
+                let new_row_size = (row_end - row_start) / arity;
+                tracker.batch_done(new_row_size, true);
+                row_start = row_end;
+                row_end += new_row_size;
+            }
+        }
+
+        while row_end < intermediate_tree_size {
+            let row_size = row_end - row_start;
+            assert_eq!(0, row_size % arity);
+            let new_row_size = row_size / arity;
+            let (new_row_start, new_row_end) = (row_end, row_end + new_row_size);
+
+            match &mut self.tree_batcher {
+                Some(batcher) => {
+                    let max_batch_size = batcher.max_batch_size();
 
                     let mut total_hashed = 0;
                     let mut batch_start = row_start;
                     while total_hashed < new_row_size {
+                        tracker.check_cancelled()?;
+
                         let batch_end = usize::min(batch_start + (max_batch_size * arity), row_end);
                         let batch_size = (batch_end - batch_start) / arity;
                         let preimages =
@@ -158,21 +350,41 @@ where
                             .copy_from_slice(&hashed);
                         total_hashed += batch_size;
                         batch_start = batch_end;
-                    }
 
-                    row_start = new_row_start;
-                    row_end = new_row_end;
+                        tracker.batch_done(batch_size, total_hashed == new_row_size);
+                    }
                 }
-            }
-            None => {
-                for i in self.leaf_count..intermediate_tree_size {
-                    tree_data[i] =
-                        Poseidon::new_with_preimage(&tree_data[start..end], &self.tree_constants)
+                None => {
+                    // Hash in batches of the configured size too, so long rows can be cancelled part way.
+                    let max_batch_size = self.config.max_batch_size;
+
+                    let mut total_hashed = 0;
+                    while total_hashed < new_row_size {
+                        tracker.check_cancelled()?;
+
+                        let batch_size = usize::min(max_batch_size, new_row_size - total_hashed);
+                        for i in total_hashed..total_hashed + batch_size {
+                            let start = row_start + i * arity;
+                            tree_data[new_row_start + i] = Poseidon::new_with_preimage(
+                                &tree_data[start..start + arity],
+                                &self.tree_constants,
+                            )
                             .hash();
-                    start += arity;
-                    end += arity;
+                        }
+                        total_hashed += batch_size;
+
+                        tracker.batch_done(batch_size, total_hashed == new_row_size);
+                    }
                 }
             }
+
+            if let (Some(checkpoint), Some(id)) = (&self.checkpoint, &tree_id) {
+                checkpoint.save_row(id, row, &tree_data[new_row_start..new_row_end])?;
+            }
+
+            row += 1;
+            row_start = new_row_start;
+            row_end = new_row_end;
         }
 
         let base_row = tree_data[..self.leaf_count].to_vec();
@@ -235,6 +447,187 @@ where
         tree_height
     }
 
+    /// Update leaves of a tree previously returned by `build_tree` (or `add_final_leaves`), recomputing only the
+    /// ancestors of the updated leaves. `base` and `tree` are the base row and cached tree as returned, built with
+    /// `rows_to_discard`; both are updated in place. Discarded rows are reconstructed from `base` where needed.
+    /// Returns the new root and the indices (into `tree`) of every node which was recomputed, in ascending order.
+    /// If any index is out of bounds or any hash fails, `base` and `tree` are left unchanged.
+    pub fn update_tree(
+        &mut self,
+        base: &mut [Fr],
+        tree: &mut [Fr],
+        rows_to_discard: usize,
+        updates: &[(usize, Fr)],
+    ) -> Result<(Fr, Vec<usize>), Error> {
+        let updated = self.rehash_updates(base, tree, rows_to_discard, updates);
+        self.release_after(updated)
+    }
+
+    fn rehash_updates(
+        &mut self,
+        base: &mut [Fr],
+        tree: &mut [Fr],
+        rows_to_discard: usize,
+        updates: &[(usize, Fr)],
+    ) -> Result<(Fr, Vec<usize>), Error> {
+        let arity = TreeArity::to_usize();
+
+        if base.len() != self.leaf_count || tree.len() != self.tree_size(rows_to_discard) {
+            return Err(Error::Other(
+                "base row or tree does not match builder".to_string(),
+            ));
+        }
+
+        if updates.iter().any(|(index, _)| *index >= self.leaf_count) {
+            return Err(Error::IndexOutOfBounds);
+        }
+
+        // Each node of the first cached row is the root of a subtree of `subtree_leaves` leaves.
+        // Since the rows below it were discarded, the subtrees of changed nodes are rehashed from the base row.
+        let subtree_leaves = arity.pow(rows_to_discard as u32 + 1);
+        let mut changed: Vec<usize> = updates
+            .iter()
+            .map(|(index, _)| index / subtree_leaves)
+            .collect();
+        changed.sort();
+        changed.dedup();
+
+        // Nothing is written to `base` or `tree` until every hash has succeeded.
+        let mut row = Vec::with_capacity(changed.len() * subtree_leaves);
+        for node in &changed {
+            row.extend_from_slice(&base[node * subtree_leaves..(node + 1) * subtree_leaves]);
+        }
+        for (index, leaf) in updates {
+            let node = changed.binary_search(&(index / subtree_leaves)).unwrap();
+            row[node * subtree_leaves + index % subtree_leaves] = *leaf;
+        }
+        for _ in 0..=rows_to_discard {
+            row = self.hash_row(&row)?;
+        }
+
+        // The new value of each recomputed node, by its index into `tree`, in ascending order.
+        let mut new_nodes = Vec::new();
+        let mut row_start = 0;
+        let mut row_size = self.leaf_count / subtree_leaves;
+        for (node, hash) in changed.iter().zip(row) {
+            new_nodes.push((row_start + node, hash));
+        }
+
+        while row_size > 1 {
+            // `changed` is sorted, so its parents are too.
+            let mut parents: Vec<usize> = changed.iter().map(|node| node / arity).collect();
+            parents.dedup();
+
+            let mut preimages = Vec::with_capacity(parents.len() * arity);
+            for parent in &parents {
+                let start = row_start + parent * arity;
+                preimages.extend_from_slice(&tree[start..start + arity]);
+            }
+            // The children changed in this row are the last `changed.len()` new nodes.
+            for (node, hash) in &new_nodes[new_nodes.len() - changed.len()..] {
+                let child = node - row_start;
+                let parent = parents.binary_search(&(child / arity)).unwrap();
+                preimages[parent * arity + child % arity] = *hash;
+            }
+            let hashes = self.hash_row(&preimages)?;
+
+            let next_row_start = row_start + row_size;
+            for (parent, hash) in parents.iter().zip(hashes) {
+                new_nodes.push((next_row_start + parent, hash));
+            }
+
+            changed = parents;
+            row_start = next_row_start;
+            row_size /= arity;
+        }
+
+        for (index, leaf) in updates {
+            base[*index] = *leaf;
+        }
+        let mut changed_indices = Vec::with_capacity(new_nodes.len());
+        for (index, hash) in new_nodes {
+            tree[index] = hash;
+            changed_indices.push(index);
+        }
+
+        Ok((tree[tree.len() - 1], changed_indices))
+    }
+
+    /// Reconstruct the rows discarded when building a tree with `rows_to_discard`, from its base row.
+    /// The result holds rows 1 through `rows_to_discard` in order, so prepending it to the cached tree
+    /// yields the tree which would have been built with no rows discarded.
+    pub fn reconstruct_discarded_rows(
+        &mut self,
+        base: &[Fr],
+        rows_to_discard: usize,
+    ) -> Result<Vec<Fr>, Error> {
+        let reconstructed = self.hash_discarded_rows(base, rows_to_discard);
+        self.release_after(reconstructed)
+    }
+
+    fn hash_discarded_rows(
+        &mut self,
+        base: &[Fr],
+        rows_to_discard: usize,
+    ) -> Result<Vec<Fr>, Error> {
+        if base.len() != self.leaf_count {
+            return Err(Error::Other("base row does not match builder".to_string()));
+        }
+
+        let mut discarded = Vec::with_capacity(self.tree_size(0) - self.tree_size(rows_to_discard));
+        let mut row_start = 0;
+        for row in 0..rows_to_discard {
+            let hashed = if row == 0 {
+                self.hash_row(base)?
+            } else {
+                self.hash_row(&discarded[row_start..])?
+            };
+            row_start = discarded.len();
+            discarded.extend(hashed);
+        }
+
+        Ok(discarded)
+    }
+
+    /// Create a tree batcher of the builder's type, if it has one.
+    fn new_batcher(&self) -> Result<Option<Batcher<'a, TreeArity>>, Error> {
+        match &self.t {
+            Some(t) => Ok(Some(Batcher::with_factory(
+                DEFAULT_STRENGTH,
+                t,
+                &self.config,
+                self.backend.as_ref(),
+            )?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Hash `preimages`, a flat sequence of `TreeArity`-sized preimages, returning one hash per preimage.
+    /// The tree batcher is only used when there are enough preimages for batching to be worthwhile.
+    fn hash_row(&mut self, preimages: &[Fr]) -> Result<Vec<Fr>, Error> {
+        let arity = TreeArity::to_usize();
+        let count = preimages.len() / arity;
+
+        if count >= MIN_BATCHED_ROW_SIZE {
+            if self.tree_batcher.is_none() {
+                self.tree_batcher = self.new_batcher()?;
+            }
+            if let Some(batcher) = &mut self.tree_batcher {
+                let max_batch_size = batcher.max_batch_size();
+                let mut hashes = Vec::with_capacity(count);
+                for chunk in preimages.chunks(max_batch_size * arity) {
+                    hashes.extend(batcher.hash(as_generic_arrays::<TreeArity>(chunk))?);
+                }
+                return Ok(hashes);
+            }
+        }
+
+        Ok(preimages
+            .chunks(arity)
+            .map(|preimage| Poseidon::new_with_preimage(preimage, &self.tree_constants).hash())
+            .collect())
+    }
+
     // Compute root of tree composed of all identical columns. For use in checking correctness of GPU tree-building
     // without the cost of generating a full tree.
     pub fn compute_uniform_tree_root(&mut self, leaf: Fr) -> Result<Fr, Error> {
@@ -254,15 +647,39 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fake_device::{fake_backends, FakeOperation};
+    use crate::progress::Progress;
     use ff::Field;
     use generic_array::typenum::U8;
     use paired::bls12_381::Fr;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+    use std::fs;
+    use std::sync::Mutex;
+    use tempdir::TempDir;
+
+    /// Create a builder hashing with batchers of type `batcher_type`, on fake devices for `BatcherType::Custom`.
+    fn new_builder(
+        batcher_type: Option<BatcherType>,
+        leaves: usize,
+        config: BatcherConfig,
+        rows_to_discard: usize,
+    ) -> TreeBuilder<'static, U8> {
+        match batcher_type {
+            Some(BatcherType::Custom) => {
+                TreeBuilder::with_backend(fake_backends().1, leaves, config, rows_to_discard)
+            }
+            _ => TreeBuilder::new(batcher_type, leaves, config, rows_to_discard),
+        }
+        .unwrap()
+    }
 
     #[test]
     fn test_tree_builder() {
         // 16KiB tree has 512 leaves.
         test_tree_builder_aux(None, 512, 32, 512, 512);
         test_tree_builder_aux(Some(BatcherType::CPU), 512, 32, 512, 512);
+        test_tree_builder_aux(Some(BatcherType::Custom), 512, 32, 512, 512);
 
         #[cfg(all(feature = "gpu", not(target_os = "macos")))]
         test_tree_builder_aux(Some(BatcherType::GPU), 512, 32, 512, 512);
@@ -278,9 +695,12 @@ mod tests {
         let batch_size = leaves / num_batches;
 
         for rows_to_discard in 0..3 {
-            let mut builder =
-                TreeBuilder::<U8>::new(batcher_type, leaves, max_tree_batch_size, rows_to_discard)
-                    .unwrap();
+            let mut builder = new_builder(
+                batcher_type,
+                leaves,
+                BatcherConfig::new(max_tree_batch_size),
+                rows_to_discard,
+            );
 
             // Simplify computing the expected root.
             let constant_element = Fr::zero();
@@ -302,6 +722,8 @@ mod tests {
                 .collect();
 
             let (base, res) = builder.add_final_leaves(final_leaves.as_slice()).unwrap();
+            // The tree batcher is released as soon as the tree is built.
+            assert!(builder.tree_batcher.is_none());
 
             let computed_root = res[res.len() - 1];
 
@@ -315,4 +737,297 @@ mod tests {
             assert_eq!(expected_root, computed_root);
         }
     }
+
+    #[test]
+    fn test_tree_builder_release() {
+        let leaves = 512;
+        let (control, factory) = fake_backends();
+        let mut builder =
+            TreeBuilder::<U8>::with_backend(factory, leaves, BatcherConfig::new(64), 0).unwrap();
+        let initial_leaves = vec![Fr::zero(); leaves];
+
+        // Each build creates a batcher, and syncs and releases it before returning.
+        let (mut base, mut tree) = builder.add_final_leaves(&initial_leaves).unwrap();
+        builder.add_final_leaves(&initial_leaves).unwrap();
+        control.assert_released(2);
+
+        // As does a failed build.
+        control.fail_next(FakeOperation::Submit, 1, Error::DecodingError);
+        assert!(builder.add_final_leaves(&initial_leaves).is_err());
+        assert!(builder.tree_batcher.is_none());
+        control.assert_released(3);
+
+        // And an update of enough subtrees to be batched.
+        let updates: Vec<(usize, Fr)> = (0..64).map(|i| (i * 8, Fr::one())).collect();
+        builder
+            .update_tree(&mut base, &mut tree, 0, &updates)
+            .unwrap();
+        assert!(builder.tree_batcher.is_none());
+        control.assert_released(4);
+
+        // And a failed update, which changes neither the base row nor the tree.
+        let (original_base, original_tree) = (base.clone(), tree.clone());
+        let updates: Vec<(usize, Fr)> = (0..64).map(|i| (i * 8, Fr::zero())).collect();
+        control.fail_next(FakeOperation::Submit, 1, Error::DecodingError);
+        assert!(builder
+            .update_tree(&mut base, &mut tree, 0, &updates)
+            .is_err());
+        assert_eq!(original_base, base);
+        assert_eq!(original_tree, tree);
+        control.assert_released(5);
+    }
+
+    #[test]
+    fn test_tree_builder_whole_tree() {
+        let leaves = 512;
+        let mut rng = XorShiftRng::from_seed(crate::TEST_SEED);
+        let initial_leaves: Vec<Fr> = (0..leaves).map(|_| Fr::random(&mut rng)).collect();
+
+        let mut row_by_row = new_builder(None, leaves, BatcherConfig::new(64), 0);
+        let (_, expected) = row_by_row.add_final_leaves(&initial_leaves).unwrap();
+
+        // A tree which fits the whole-tree entry point is built with one call to it.
+        let (control, factory) = fake_backends();
+        control.set_whole_tree_leaves(Some(leaves));
+        let mut builder =
+            TreeBuilder::<U8>::with_backend(factory, leaves, BatcherConfig::new(64), 0).unwrap();
+        let (_, tree) = builder.add_final_leaves(&initial_leaves).unwrap();
+        assert_eq!(1, control.calls(FakeOperation::BuildTree));
+        assert_eq!(0, control.calls(FakeOperation::Submit));
+        assert_eq!(expected, tree);
+
+        // Other trees are built row by row.
+        let (control, factory) = fake_backends();
+        control.set_whole_tree_leaves(Some(leaves));
+        let mut builder =
+            TreeBuilder::<U8>::with_backend(factory, leaves / 8, BatcherConfig::new(64), 0)
+                .unwrap();
+        builder
+            .add_final_leaves(&initial_leaves[..leaves / 8])
+            .unwrap();
+        assert_eq!(0, control.calls(FakeOperation::BuildTree));
+    }
+
+    #[test]
+    fn test_select_build_strategy() {
+        use TreeBuildStrategy::*;
+
+        assert_eq!(WholeTree, select_build_strategy(Some(512), 512, 0));
+        // Other tree sizes, batchers without a whole-tree entry point and resumed builds are hashed row by row.
+        assert_eq!(RowByRow, select_build_strategy(Some(512), 64, 0));
+        assert_eq!(RowByRow, select_build_strategy(None, 512, 0));
+        assert_eq!(RowByRow, select_build_strategy(Some(512), 512, 1));
+    }
+
+    #[test]
+    fn test_update_tree() {
+        // Few updates are hashed directly, many are batched.
+        test_update_tree_aux(None, 512, 5);
+        test_update_tree_aux(Some(BatcherType::CPU), 512, 5);
+        test_update_tree_aux(Some(BatcherType::CPU), 512, 200);
+        test_update_tree_aux(Some(BatcherType::Custom), 512, 200);
+
+        #[cfg(all(feature = "gpu", not(target_os = "macos")))]
+        test_update_tree_aux(Some(BatcherType::GPU), 512, 200);
+    }
+
+    #[test]
+    fn test_update_tree_out_of_bounds() {
+        let leaves = 512;
+        let mut rng = XorShiftRng::from_seed(crate::TEST_SEED);
+        let mut builder = new_builder(Some(BatcherType::CPU), leaves, BatcherConfig::new(512), 0);
+
+        let initial_leaves: Vec<Fr> = (0..leaves).map(|_| Fr::random(&mut rng)).collect();
+        let (mut base, mut tree) = builder.add_final_leaves(&initial_leaves).unwrap();
+        let (original_base, original_tree) = (base.clone(), tree.clone());
+
+        // An out-of-bounds index after valid ones changes nothing.
+        let mut updates: Vec<(usize, Fr)> =
+            (0..64).map(|i| (i * 8, Fr::random(&mut rng))).collect();
+        updates.push((leaves, Fr::one()));
+        match builder.update_tree(&mut base, &mut tree, 0, &updates) {
+            Err(Error::IndexOutOfBounds) => (),
+            other => panic!("expected an out-of-bounds error, got {:?}", other),
+        }
+        assert_eq!(original_base, base);
+        assert_eq!(original_tree, tree);
+    }
+
+    fn test_update_tree_aux(batcher_type: Option<BatcherType>, leaves: usize, update_count: usize) {
+        let mut rng = XorShiftRng::from_seed(crate::TEST_SEED);
+
+        for rows_to_discard in 0..3 {
+            let mut builder = new_builder(
+                batcher_type,
+                leaves,
+                BatcherConfig::new(512),
+                rows_to_discard,
+            );
+
+            let initial_leaves: Vec<Fr> = (0..leaves).map(|_| Fr::random(&mut rng)).collect();
+            let (mut base, mut tree) = builder.add_final_leaves(&initial_leaves).unwrap();
+            let original_tree = tree.clone();
+
+            // 7 is coprime with the leaf count, so the updated indices are distinct.
+            let updates: Vec<(usize, Fr)> = (0..update_count)
+                .map(|i| ((i * 7) % leaves, Fr::random(&mut rng)))
+                .collect();
+
+            let (root, changed) = builder
+                .update_tree(&mut base, &mut tree, rows_to_discard, &updates)
+                .unwrap();
+
+            let (expected_base, expected_tree) = builder.add_final_leaves(&base).unwrap();
+
+            assert_eq!(expected_base, base);
+            assert_eq!(expected_tree, tree);
+            assert_eq!(expected_tree[expected_tree.len() - 1], root);
+
+            // Only reported nodes may have been recomputed.
+            for (i, (original, updated)) in original_tree.iter().zip(tree.iter()).enumerate() {
+                if original != updated {
+                    assert!(changed.contains(&i), "unreported change at {}", i);
+                }
+            }
+            assert!(changed.contains(&(tree.len() - 1)));
+        }
+    }
+
+    #[test]
+    fn test_tree_builder_progress() {
+        test_tree_builder_progress_aux(None);
+        test_tree_builder_progress_aux(Some(BatcherType::CPU));
+    }
+
+    fn test_tree_builder_progress_aux(batcher_type: Option<BatcherType>) {
+        let leaves = 512;
+        // Small batches, so the first row is hashed in several.
+        let mut builder =
+            TreeBuilder::<U8>::new(batcher_type, leaves, BatcherConfig::new(16), 0).unwrap();
+
+        let reports = Arc::new(Mutex::new(Vec::<Progress>::new()));
+        let reports_clone = reports.clone();
+        builder.set_progress_observer(Arc::new(move |progress: &Progress| {
+            reports_clone.lock().unwrap().push(progress.clone());
+        }));
+
+        let (_, tree) = builder.add_final_leaves(&vec![Fr::zero(); leaves]).unwrap();
+
+        let reports = reports.lock().unwrap();
+        let last = reports.last().expect("no progress reported");
+        assert_eq!(builder.tree_height(), last.total_rows);
+        assert_eq!(last.total_rows, last.rows_done);
+        assert_eq!(tree.len(), last.total_hashes);
+        assert_eq!(last.total_hashes, last.hashes_done);
+        assert!(reports
+            .windows(2)
+            .all(|pair| pair[0].hashes_done <= pair[1].hashes_done));
+    }
+
+    #[test]
+    fn test_tree_builder_cancellation() {
+        test_tree_builder_cancellation_aux(None);
+        test_tree_builder_cancellation_aux(Some(BatcherType::CPU));
+    }
+
+    fn test_tree_builder_cancellation_aux(batcher_type: Option<BatcherType>) {
+        let leaves = 512;
+        let mut builder =
+            TreeBuilder::<U8>::new(batcher_type, leaves, BatcherConfig::new(16), 0).unwrap();
+
+        // Cancel as soon as the first batch has been hashed.
+        let token = CancellationToken::new();
+        let token_clone = token.clone();
+        let reports = Arc::new(Mutex::new(Vec::<Progress>::new()));
+        let reports_clone = reports.clone();
+        builder.set_cancellation_token(token.clone());
+        builder.set_progress_observer(Arc::new(move |progress: &Progress| {
+            reports_clone.lock().unwrap().push(progress.clone());
+            token_clone.cancel();
+        }));
+
+        match builder.add_final_leaves(&vec![Fr::zero(); leaves]) {
+            Err(Error::Cancelled) => (),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("build was not cancelled"),
+        }
+        assert!(token.is_cancelled());
+
+        // The build stopped part way through the first row.
+        let reports = reports.lock().unwrap();
+        assert_eq!(1, reports.len());
+        assert_eq!(0, reports[0].rows_done);
+        assert_eq!(16, reports[0].hashes_done);
+    }
+
+    #[test]
+    fn test_tree_builder_checkpoint() {
+        test_tree_builder_checkpoint_aux(None);
+        test_tree_builder_checkpoint_aux(Some(BatcherType::CPU));
+    }
+
+    fn test_tree_builder_checkpoint_aux(batcher_type: Option<BatcherType>) {
+        let leaves = 512;
+        let mut rng = XorShiftRng::from_seed(crate::TEST_SEED);
+        let data: Vec<Fr> = (0..leaves).map(|_| Fr::random(&mut rng)).collect();
+
+        let mut builder =
+            TreeBuilder::<U8>::new(batcher_type, leaves, BatcherConfig::new(16), 0).unwrap();
+        let (expected_base, expected_tree) = builder.add_final_leaves(&data).unwrap();
+
+        let tmp = TempDir::new("neptune-tree-checkpoint").unwrap();
+        let checkpoint = Checkpoint::new(tmp.path()).unwrap();
+        let first_row = tmp.path().join("row-0");
+        let assert_invalid = |result: Result<(Vec<Fr>, Vec<Fr>), Error>| match result {
+            Err(Error::InvalidCheckpoint(_)) => (),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("invalid checkpoint was accepted"),
+        };
+
+        // Simulate a crash once the first row is complete, by cancelling the build.
+        let mut builder =
+            TreeBuilder::<U8>::new(batcher_type, leaves, BatcherConfig::new(16), 0).unwrap();
+        let token = CancellationToken::new();
+        let token_clone = token.clone();
+        builder.set_checkpoint(checkpoint.clone());
+        builder.set_cancellation_token(token);
+        builder.set_progress_observer(Arc::new(move |progress: &Progress| {
+            if progress.rows_done == 1 {
+                token_clone.cancel();
+            }
+        }));
+        assert!(builder.add_final_leaves(&data).is_err());
+        assert!(first_row.exists());
+        assert!(!tmp.path().join("row-1").exists());
+
+        let mut builder =
+            TreeBuilder::<U8>::new(batcher_type, leaves, BatcherConfig::new(16), 0).unwrap();
+        builder.set_checkpoint(checkpoint.clone());
+
+        // A checkpoint of other leaves is rejected.
+        let mut other_data = data.clone();
+        other_data[17] = Fr::random(&mut rng);
+        assert_invalid(builder.add_final_leaves(&other_data));
+
+        // A corrupted checkpoint is rejected.
+        let bytes = fs::read(&first_row).unwrap();
+        let mut corrupted = bytes.clone();
+        corrupted[100] ^= 1;
+        fs::write(&first_row, &corrupted).unwrap();
+        assert_invalid(builder.add_final_leaves(&data));
+
+        // The intact checkpoint is resumed from, producing the same tree.
+        fs::write(&first_row, &bytes).unwrap();
+        let reports = Arc::new(Mutex::new(Vec::<Progress>::new()));
+        let reports_clone = reports.clone();
+        builder.set_progress_observer(Arc::new(move |progress: &Progress| {
+            reports_clone.lock().unwrap().push(progress.clone());
+        }));
+        let (base, tree) = builder.add_final_leaves(&data).unwrap();
+
+        assert_eq!(expected_base, base);
+        assert_eq!(expected_tree, tree);
+        assert_eq!(1, reports.lock().unwrap()[0].rows_done);
+        assert!(!first_row.exists(), "checkpoint was not cleared");
+    }
 }
@@ -0,0 +1,217 @@
+use crate::error::Error;
+use crate::poseidon::{Poseidon, PoseidonConstants};
+use crate::{Arity, BatchHasher, Strength};
+use generic_array::GenericArray;
+use log::warn;
+use paired::bls12_381::{Bls12, Fr};
+use std::marker::PhantomData;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Counts of the hashes checked by a `VerifyingBatchHasher`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VerificationStats {
+    /// Number of batches hashed.
+    pub batches: usize,
+    /// Number of hashes re-computed on the CPU.
+    pub checked: usize,
+    /// Number of hashes which did not match the CPU's.
+    pub failed: usize,
+}
+
+/// `VerifyingBatchHasher` checks the hashes of another `BatchHasher`, such as a GPU, by re-computing a random sample
+/// of each batch with `Poseidon` on the CPU. If any sampled hash differs, the batch fails with
+/// `Error::HashMismatch`, which makes a `FallbackBatcher` switch to the CPU.
+pub struct VerifyingBatchHasher<H, A>
+where
+    H: BatchHasher<A>,
+    A: Arity<Fr>,
+{
+    inner: H,
+    constants: PoseidonConstants<Bls12, A>,
+    samples: usize,
+    rng: XorShift,
+    stats: VerificationStats,
+    _a: PhantomData<A>,
+}
+
+impl<H, A> VerifyingBatchHasher<H, A>
+where
+    H: BatchHasher<A>,
+    A: Arity<Fr>,
+{
+    /// Verify `samples` hashes of each batch hashed by `inner`, which hashes with `strength`. Every hash of batches of
+    /// at most `samples` preimages is verified.
+    pub fn new(inner: H, strength: Strength, samples: usize) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+
+        Self {
+            inner,
+            constants: PoseidonConstants::new_with_strength(strength),
+            samples,
+            rng: XorShift::new(seed),
+            stats: Default::default(),
+            _a: PhantomData::<A>,
+        }
+    }
+
+    pub fn stats(&self) -> &VerificationStats {
+        &self.stats
+    }
+
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+
+    /// Indices of the hashes of a batch of `count` to verify.
+    fn sample(&mut self, count: usize) -> Vec<usize> {
+        if count <= self.samples {
+            (0..count).collect()
+        } else {
+            (0..self.samples)
+                .map(|_| self.rng.next_below(count))
+                .collect()
+        }
+    }
+}
+
+impl<H, A> BatchHasher<A> for VerifyingBatchHasher<H, A>
+where
+    H: BatchHasher<A>,
+    A: Arity<Fr>,
+{
+    fn hash(&mut self, preimages: &[GenericArray<Fr, A>]) -> Result<Vec<Fr>, Error> {
+        let hashes = self.inner.hash(preimages)?;
+        if hashes.len() != preimages.len() {
+            return Err(Error::GPUError(format!(
+                "expected {} hashes, but got {}",
+                preimages.len(),
+                hashes.len()
+            )));
+        }
+        self.stats.batches += 1;
+
+        let mut mismatch = None;
+        for index in self.sample(preimages.len()) {
+            let expected = Poseidon::new_with_preimage(&preimages[index], &self.constants).hash();
+            self.stats.checked += 1;
+
+            if hashes[index] != expected {
+                warn!("Hash of preimage {} in batch does not match the CPU", index);
+                self.stats.failed += 1;
+                mismatch = mismatch.or(Some(index));
+            }
+        }
+
+        match mismatch {
+            Some(index) => Err(Error::HashMismatch { index }),
+            None => Ok(hashes),
+        }
+    }
+
+    fn max_batch_size(&self) -> usize {
+        self.inner.max_batch_size()
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        self.inner.sync()
+    }
+
+    fn release(&mut self) -> Result<(), Error> {
+        self.inner.release()
+    }
+}
+
+/// Xorshift generator for choosing samples. Verification needs unpredictable samples, not cryptographic randomness.
+#[derive(Debug)]
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        // The state must never be zero.
+        Self(seed | 1)
+    }
+
+    fn next_below(&mut self, bound: usize) -> usize {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+
+        (x % bound as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::poseidon::SimplePoseidonBatchHasher;
+    use crate::scalar_from_u64;
+    use ff::Field;
+    use generic_array::sequence::GenericSequence;
+    use generic_array::typenum::U2;
+
+    /// Hashes correctly, except for adding one to the hash at `corrupt`.
+    struct CorruptHasher {
+        inner: SimplePoseidonBatchHasher<'static, U2>,
+        corrupt: Option<usize>,
+    }
+
+    impl BatchHasher<U2> for CorruptHasher {
+        fn hash(&mut self, preimages: &[GenericArray<Fr, U2>]) -> Result<Vec<Fr>, Error> {
+            let mut hashes = self.inner.hash(preimages)?;
+            if let Some(index) = self.corrupt {
+                hashes[index].add_assign(&Fr::one());
+            }
+            Ok(hashes)
+        }
+    }
+
+    #[test]
+    fn test_verifying_batch_hasher() {
+        let preimages: Vec<GenericArray<Fr, U2>> = (0..10)
+            .map(|n| GenericArray::generate(|i| scalar_from_u64::<Fr>((2 * n + i) as u64)))
+            .collect();
+        let corrupt = CorruptHasher {
+            inner: SimplePoseidonBatchHasher::new(100).unwrap(),
+            corrupt: None,
+        };
+
+        // Every hash of a batch no larger than the sample is checked.
+        let mut hasher = VerifyingBatchHasher::new(corrupt, Strength::Standard, 10);
+        assert_eq!(10, hasher.hash(&preimages).unwrap().len());
+
+        hasher.inner.corrupt = Some(7);
+        match hasher.hash(&preimages) {
+            Err(Error::HashMismatch { index: 7 }) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(
+            &VerificationStats {
+                batches: 2,
+                checked: 20,
+                failed: 1,
+            },
+            hasher.stats()
+        );
+
+        // Larger batches are sampled.
+        let mut hasher = VerifyingBatchHasher::new(hasher.into_inner(), Strength::Standard, 3);
+        hasher.inner.corrupt = None;
+        assert!(hasher.hash(&preimages).is_ok());
+        assert_eq!(3, hasher.stats().checked);
+    }
+
+    #[test]
+    fn test_xorshift_bounds() {
+        let mut rng = XorShift::new(0);
+        assert!((0..1000).map(|_| rng.next_below(7)).all(|n| n < 7));
+    }
+}